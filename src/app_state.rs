@@ -1,6 +1,16 @@
 use std::ops::{ Deref, DerefMut };
 
-use druid::{ Data, Lens };
+use druid::{ im::Vector, Data, Lens };
+use tinyjson::JsonValue;
+
+use crate::{
+    instance::InstanceId,
+    log_buffer::{ LogLevelFilter, LogLine },
+    mirror::{ default_mirrors, Mirror },
+    process::ExistingProcessPolicy,
+    stage::ConnectionState,
+    update::{ UpdateChannel, UpdatePolicy },
+};
 
 #[derive(Debug, Clone)]
 pub struct TimerTokenData(pub druid::TimerToken);
@@ -34,7 +44,7 @@ impl Default for TimerTokenData {
 pub struct AppState {
     pub disabled: bool,
     pub token: String,
-    pub start_button: &'static str,
+    pub connection: ConnectionState,
     pub ip: String,
     pub run_time: usize,
     pub warning: String,
@@ -44,7 +54,61 @@ pub struct AppState {
     pub auto_restart: bool,
     pub fast_mode: bool,
     pub debug_mode: bool,
-    pub kill_hiper_when_start: bool,
+    /// 离线模式，不会下载或更新 HiPer、驱动和插件
+    pub offline_mode: bool,
+    pub existing_process_policy: ExistingProcessPolicy,
+    pub extra_args: String,
+    pub hiper_path: String,
+    pub restart_delay_secs: u64,
+    pub restart_max_delay_secs: u64,
+    pub restart_max_retries: u32,
+    pub restart_stable_secs: u64,
+    pub stop_timeout_secs: u64,
+    /// 等待 HiPer 入网的秒数，为 0 时不限制
+    pub join_timeout_secs: u64,
+    /// 保留的历史日志份数，为 0 时不保留
+    pub log_history_count: u64,
+    /// 历史日志的总大小上限（MB），为 0 时不限制
+    pub log_history_max_mb: u64,
+    pub update_policy: UpdatePolicy,
+    pub update_channel: UpdateChannel,
+    /// 固定使用的 HiPer 版本号或 SHA-1 摘要
+    pub pinned_version: String,
+    /// 两次检查 HiPer 更新之间的最短间隔小时数，为 0 时每次运行都会检查
+    pub update_check_interval_hours: u64,
+    /// 上次检查 HiPer 更新的时间戳（秒），从未检查过时为 0
+    pub last_update_check: i64,
+    /// 已安装的 HiPer 版本号，未知时为空
+    pub hiper_version: String,
+    /// 镜像中可用的 HiPer 新版本号，已是最新版本时为空
+    pub available_hiper_version: String,
+    /// 镜像中 HiPer 新版本的更新日志
+    pub available_hiper_changelog: String,
+    /// 启动时自动检查并安装 HiPer Bridge 的新版本
+    pub bridge_auto_update: bool,
+    /// 代理服务器地址，为空时使用 `HTTP_PROXY` 等环境变量
+    pub proxy: String,
+    /// 不使用代理的主机列表，为空时使用 `NO_PROXY` 环境变量
+    pub no_proxy: String,
+    /// 按顺序尝试的下载镜像
+    pub mirrors: Vector<Mirror>,
+    /// 上一次下载成功的镜像根地址
+    pub last_mirror: String,
+    /// 除主网络外同时加入的其它网络
+    pub networks: Vector<NetworkState>,
+    /// 多网络页中正在填写的新网络凭证
+    pub new_network_token: String,
+    /// 日志页中显示的最近的日志
+    pub log_lines: Vector<LogLine>,
+    pub log_level_filter: LogLevelFilter,
+    /// 日志页的搜索文字
+    pub log_search: String,
+    /// 暂停后日志页不再显示新的日志，恢复后会补上暂停期间的日志
+    pub log_paused: bool,
+    /// 日志页中按筛选条件显示的行，由 [`crate::log_buffer::refresh_view`] 在日志或筛选条件变化时更新
+    pub log_view: Vector<LogLine>,
+    /// 日志页中选中的起止行的序号，没有选中时为空
+    pub log_selection: Option<(u64, u64)>,
     #[cfg(target_os = "macos")]
     pub init_message: String,
     #[cfg(target_os = "macos")]
@@ -56,7 +120,7 @@ impl Default for AppState {
         Self {
             disabled: false,
             token: "".into(),
-            start_button: "启动",
+            connection: ConnectionState::default(),
             ip: "".into(),
             warning: "".into(),
             run_time: 0,
@@ -66,7 +130,39 @@ impl Default for AppState {
             auto_restart: true,
             fast_mode: false,
             debug_mode: false,
-            kill_hiper_when_start: true,
+            offline_mode: false,
+            existing_process_policy: ExistingProcessPolicy::StopOwned,
+            extra_args: "".into(),
+            hiper_path: "".into(),
+            restart_delay_secs: 5,
+            restart_max_delay_secs: 60,
+            restart_max_retries: 5,
+            restart_stable_secs: 60,
+            stop_timeout_secs: 5,
+            join_timeout_secs: 60,
+            log_history_count: 10,
+            log_history_max_mb: 50,
+            update_policy: UpdatePolicy::Auto,
+            update_channel: UpdateChannel::Stable,
+            pinned_version: "".into(),
+            update_check_interval_hours: 0,
+            last_update_check: 0,
+            hiper_version: "".into(),
+            available_hiper_version: "".into(),
+            available_hiper_changelog: "".into(),
+            bridge_auto_update: true,
+            proxy: "".into(),
+            no_proxy: "".into(),
+            mirrors: default_mirrors().into(),
+            last_mirror: "".into(),
+            networks: Vector::new(),
+            new_network_token: "".into(),
+            log_lines: Vector::new(),
+            log_level_filter: LogLevelFilter::All,
+            log_search: "".into(),
+            log_paused: false,
+            log_view: Vector::new(),
+            log_selection: None,
             #[cfg(target_os = "macos")]
             init_message: "".into(),
             #[cfg(target_os = "macos")]
@@ -74,3 +170,120 @@ impl Default for AppState {
         }
     }
 }
+
+impl AppState {
+    pub fn network(&self, id: InstanceId) -> Option<&NetworkState> {
+        self.networks.iter().find(|x| x.id == id)
+    }
+
+    pub fn network_mut(&mut self, id: InstanceId) -> Option<&mut NetworkState> {
+        self.networks.iter_mut().find(|x| x.id == id)
+    }
+
+    /// 为新添加的网络分配一个未被使用的实例编号
+    pub fn next_network_id(&self) -> InstanceId {
+        self.networks
+            .iter()
+            .map(|x| x.id + 1)
+            .max()
+            .unwrap_or(1)
+    }
+}
+
+/// 主网络以外的一个网络在界面中的状态，字段含义和 [`AppState`] 中的同名字段一致
+#[derive(Debug, Clone, Data, Lens)]
+pub struct NetworkState {
+    pub id: InstanceId,
+    pub token: String,
+    pub disabled: bool,
+    pub connection: ConnectionState,
+    pub ip: String,
+    pub run_time: usize,
+    pub warning: String,
+}
+
+impl NetworkState {
+    pub fn new(id: InstanceId, token: String) -> Self {
+        Self {
+            id,
+            token,
+            disabled: false,
+            connection: ConnectionState::default(),
+            ip: "".into(),
+            run_time: 0,
+            warning: "".into(),
+        }
+    }
+
+    /// 从配置文件中读取网络，旧版本只保存了凭证，此时使用 `fallback_id` 作为实例编号
+    pub fn from_json(value: &JsonValue, fallback_id: InstanceId) -> Option<Self> {
+        match value {
+            JsonValue::String(token) => Some(Self::new(fallback_id, token.to_owned())),
+            JsonValue::Object(data) => {
+                let token = data.get("token")?.get::<String>()?.to_owned();
+                let id = data
+                    .get("id")
+                    .and_then(|x| x.get::<f64>())
+                    .filter(|x| x.fract() == 0.0 && **x >= 1.0 && **x <= (u32::MAX as f64))
+                    .map(|x| *x as InstanceId)
+                    .unwrap_or(fallback_id);
+                Some(Self::new(id, token))
+            }
+            _ => None,
+        }
+    }
+
+    /// 保存到配置文件中的数据，包括实例编号，使网络的工作目录和日志在重启后保持不变
+    pub fn to_json(&self) -> JsonValue {
+        let mut data = std::collections::HashMap::with_capacity(2);
+        data.insert("id".into(), JsonValue::Number(self.id as f64));
+        data.insert("token".into(), JsonValue::String(self.token.to_owned()));
+        JsonValue::Object(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn network_json_round_trip_keeps_id() {
+        let network = NetworkState::new(7, "token".into());
+        let loaded = NetworkState::from_json(&network.to_json(), 1).unwrap();
+        assert_eq!(loaded.id, 7);
+        assert_eq!(loaded.token, "token");
+    }
+
+    #[test]
+    fn network_from_json_uses_fallback_id() {
+        let cases = [
+            (r#""legacy-token""#, Some((3, "legacy-token"))),
+            (r#"{"token":"a"}"#, Some((3, "a"))),
+            (r#"{"id":0,"token":"a"}"#, Some((3, "a"))),
+            (r#"{"id":1.5,"token":"a"}"#, Some((3, "a"))),
+            (r#"{"id":"2","token":"a"}"#, Some((3, "a"))),
+            (r#"{"id":2,"token":"a"}"#, Some((2, "a"))),
+            (r#"{"id":2}"#, None),
+            ("42", None),
+        ];
+        for (json, expected) in cases {
+            let value = json.parse::<JsonValue>().unwrap();
+            let loaded = NetworkState::from_json(&value, 3);
+            assert_eq!(
+                loaded.as_ref().map(|x| (x.id, x.token.as_str())),
+                expected,
+                "{}",
+                json
+            );
+        }
+    }
+
+    #[test]
+    fn next_network_id_is_after_largest() {
+        let mut data = AppState::default();
+        assert_eq!(data.next_network_id(), 1);
+        data.networks.push_back(NetworkState::new(5, "a".into()));
+        data.networks.push_back(NetworkState::new(2, "b".into()));
+        assert_eq!(data.next_network_id(), 6);
+    }
+}
@@ -0,0 +1,231 @@
+//! 离线安装包的导入
+//!
+//! 离线安装包是一个 zip 压缩包，目录结构和下载镜像相同：根目录下的 `packages.sha1`（以及可选的
+//! `packages.sha256`）、各架构目录下的 HiPer 程序和 WinTUN，以及根目录下的 WinTAP 安装程序。
+//! 压缩包内的文件可以整体位于一个文件夹中。导入时只会安装适用于当前系统的文件，
+//! 所有文件都必须先通过安装包内索引的校验
+
+use std::{ fs::File, io::Read, path::{ Path, PathBuf } };
+
+use anyhow::Context;
+use druid::{ ExtEventSink, Target };
+
+use crate::{
+    hiper::{ get_hiper_dir, HIPER_FILE_NAME },
+    instance::{ self, PRIMARY_INSTANCE },
+    integrity::PackageIndex,
+    ui::{ SET_DISABLED, SET_WARNING },
+    utils::{ path_with_suffix, replace_file_atomic },
+    DynResult,
+};
+#[cfg(windows)]
+use crate::hiper::{ TAP_INSTALLER_DOWNLOAD_NAME, TAP_INSTALLER_FILE_NAME, WINTUN_FILE_NAME };
+
+struct Bundle {
+    archive: zip::ZipArchive<File>,
+    /// 安装包内容所在的文件夹，位于压缩包根目录时为空
+    prefix: String,
+    index: PackageIndex,
+}
+
+impl Bundle {
+    fn open(path: &Path) -> DynResult<Self> {
+        let file = File::open(path).context("无法打开离线安装包")?;
+        let mut archive = zip::ZipArchive::new(file).context("无法解析离线安装包，请确认是否为 zip 压缩包")?;
+        let index_name = archive
+            .file_names()
+            .find(|x| *x == "packages.sha1" || x.ends_with("/packages.sha1"))
+            .map(str::to_owned)
+            .context("离线安装包中没有 packages.sha1，无法校验文件")?;
+        let prefix = index_name.trim_end_matches("packages.sha1").to_owned();
+        let sha1 = read_entry(&mut archive, &index_name)?;
+        let sha256 = read_entry(&mut archive, &format!("{}packages.sha256", prefix)).ok();
+        let index = PackageIndex::parse(
+            &String::from_utf8_lossy(&sha1),
+            sha256.map(|x| String::from_utf8_lossy(&x).to_string()).as_deref()
+        );
+        Ok(Self {
+            archive,
+            prefix,
+            index,
+        })
+    }
+
+    /// 读取并校验安装包中的文件，`path` 为索引中的路径，索引中没有该文件时返回 `None`
+    fn read_verified(&mut self, path: Option<String>) -> DynResult<Option<Vec<u8>>> {
+        let path = match path {
+            Some(path) => path,
+            None => {
+                return Ok(None);
+            }
+        };
+        let data = read_entry(&mut self.archive, &format!("{}{}", self.prefix, path))?;
+        self.index.verify(&path, &data)?;
+        Ok(Some(data))
+    }
+}
+
+fn read_entry(archive: &mut zip::ZipArchive<File>, name: &str) -> DynResult<Vec<u8>> {
+    let mut entry = archive
+        .by_name(name)
+        .with_context(|| format!("离线安装包中没有 {}", name))?;
+    let mut data = Vec::with_capacity(entry.size() as usize);
+    entry.read_to_end(&mut data).with_context(|| format!("无法读取离线安装包中的 {}", name))?;
+    Ok(data)
+}
+
+/// 从离线安装包安装 HiPer 和驱动，返回安装了的文件名
+///
+/// 安装包中必须包含适用于当前系统的 HiPer，WinTUN 和 WinTAP 安装程序则是可选的。
+/// 原有的 HiPer 会被备份为 `.bak` 文件
+pub fn import_bundle(path: &Path) -> DynResult<Vec<&'static str>> {
+    if instance::is_any_running() {
+        anyhow::bail!("请先停止所有正在运行的 HiPer 再导入离线安装包");
+    }
+    let arch = crate::utils::get_system_arch().to_string();
+    let hiper_dir = get_hiper_dir()?;
+    std::fs::create_dir_all(&hiper_dir).context("无法创建 HiPer 安装目录")?;
+    install_bundle(path, &hiper_dir, &arch)
+}
+
+/// 将离线安装包中 `arch` 架构的文件安装到 `hiper_dir`，见 [`import_bundle`]
+///
+/// 所有文件都会先写入临时文件再替换，写入中断时原有的文件不会被破坏
+fn install_bundle(path: &Path, hiper_dir: &Path, arch: &str) -> DynResult<Vec<&'static str>> {
+    let mut bundle = Bundle::open(path)?;
+    let mut installed = vec![];
+
+    let hiper_path = bundle.index.find(arch, HIPER_FILE_NAME).map(str::to_owned);
+    let hiper = bundle
+        .read_verified(hiper_path)?
+        .with_context(|| format!("离线安装包中没有适用于当前系统（{}）的 HiPer", arch))?;
+
+    #[cfg(windows)]
+    {
+        let wintun_path = bundle.index.find(arch, WINTUN_FILE_NAME).map(str::to_owned);
+        if let Some(wintun) = bundle.read_verified(wintun_path)? {
+            let wintun_path = hiper_dir.join(WINTUN_FILE_NAME);
+            let wintun_disabled_path = path_with_suffix(&wintun_path, ".disabled");
+            // 使用 TAP 时 WinTUN 处于禁用状态，需要保持禁用
+            if wintun_disabled_path.exists() {
+                replace_file_atomic(&wintun_disabled_path, &wintun, None).context("无法安装 WinTUN")?;
+            } else {
+                replace_file_atomic(&wintun_path, &wintun, None).context("无法安装 WinTUN")?;
+            }
+            installed.push(WINTUN_FILE_NAME);
+        }
+
+        let tap_path = bundle.index
+            .sha1_of(TAP_INSTALLER_DOWNLOAD_NAME)
+            .map(|_| TAP_INSTALLER_DOWNLOAD_NAME.to_owned());
+        if let Some(tap) = bundle.read_verified(tap_path)? {
+            replace_file_atomic(hiper_dir.join(TAP_INSTALLER_FILE_NAME), &tap, None).context(
+                "无法写入 WinTAP 安装程序"
+            )?;
+            installed.push(TAP_INSTALLER_DOWNLOAD_NAME);
+        }
+    }
+
+    // HiPer 最后安装，避免驱动安装失败时只更新了一部分文件
+    let installed_hiper_path = hiper_dir.join(HIPER_FILE_NAME);
+    replace_file_atomic(
+        &installed_hiper_path,
+        &hiper,
+        Some(&path_with_suffix(&installed_hiper_path, ".bak"))
+    ).context("无法安装 HiPer 程序")?;
+    installed.insert(0, HIPER_FILE_NAME);
+
+    Ok(installed)
+}
+
+/// 在后台线程中导入离线安装包，导入结果会显示在主网络的提示中
+pub fn import_bundle_in_thread(ctx: ExtEventSink, path: PathBuf) {
+    std::thread::spawn(move || {
+        let _ = ctx.submit_command(SET_DISABLED, (PRIMARY_INSTANCE, true), Target::Auto);
+        let message = match import_bundle(&path) {
+            Ok(installed) => format!("已从离线安装包安装：{}", installed.join("、")),
+            Err(err) => format!("导入离线安装包失败：{:?}", err),
+        };
+        crate::log_buffer::info(message.as_str());
+        let _ = ctx.submit_command(SET_WARNING, (PRIMARY_INSTANCE, message), Target::Auto);
+        let _ = ctx.submit_command(SET_DISABLED, (PRIMARY_INSTANCE, false), Target::Auto);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ Cursor, Write };
+
+    use zip::{ write::FileOptions, ZipWriter };
+
+    use super::*;
+    use crate::integrity::{ sha1_hex, sha256_hex };
+
+    const ARCH: &str = "linux-amd64";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hiper-bridge-bundle-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// 生成安装包，索引中记录的是 `hiper` 的摘要，压缩包中写入的是 `packed`
+    fn write_bundle(dir: &Path, hiper: &[u8], packed: &[u8]) -> PathBuf {
+        let entry = format!("{}/{}", ARCH, HIPER_FILE_NAME);
+        let sha1 = format!("{}  {}\n", sha1_hex(hiper), entry);
+        let sha256 = format!("{}  {}\n", sha256_hex(hiper), entry);
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        for (name, data) in [
+            ("bundle/packages.sha1".to_owned(), sha1.as_bytes()),
+            ("bundle/packages.sha256".to_owned(), sha256.as_bytes()),
+            (format!("bundle/{}", entry), packed),
+        ] {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        let path = dir.join("bundle.zip");
+        std::fs::write(&path, zip.finish().unwrap().into_inner()).unwrap();
+        path
+    }
+
+    #[test]
+    fn installs_verified_hiper_and_keeps_backup() {
+        let dir = temp_dir("good");
+        let hiper_path = dir.join(HIPER_FILE_NAME);
+        std::fs::write(&hiper_path, b"old hiper").unwrap();
+        let bundle = write_bundle(&dir, b"new hiper", b"new hiper");
+
+        assert_eq!(install_bundle(&bundle, &dir, ARCH).unwrap(), [HIPER_FILE_NAME]);
+
+        assert_eq!(std::fs::read(&hiper_path).unwrap(), b"new hiper");
+        assert_eq!(std::fs::read(path_with_suffix(&hiper_path, ".bak")).unwrap(), b"old hiper");
+        assert!(!path_with_suffix(&hiper_path, ".tmp").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn rejects_tampered_entry() {
+        let dir = temp_dir("tampered");
+        let hiper_path = dir.join(HIPER_FILE_NAME);
+        std::fs::write(&hiper_path, b"old hiper").unwrap();
+        let bundle = write_bundle(&dir, b"new hiper", b"evil hiper");
+
+        assert!(install_bundle(&bundle, &dir, ARCH).is_err());
+
+        assert_eq!(std::fs::read(&hiper_path).unwrap(), b"old hiper");
+        assert!(!path_with_suffix(&hiper_path, ".bak").exists());
+        assert!(!path_with_suffix(&hiper_path, ".tmp").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn rejects_bundle_for_other_arch() {
+        let dir = temp_dir("arch");
+        let bundle = write_bundle(&dir, b"new hiper", b"new hiper");
+
+        assert!(install_bundle(&bundle, &dir, "windows-arm64").is_err());
+        assert!(!dir.join(HIPER_FILE_NAME).exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
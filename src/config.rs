@@ -1,4 +1,11 @@
-use crate::{ app_state::AppState, hiper::get_hiper_dir, DynResult };
+use crate::{
+    app_state::{ AppState, NetworkState },
+    hiper::get_hiper_dir,
+    mirror::Mirror,
+    process::ExistingProcessPolicy,
+    update::{ UpdateChannel, UpdatePolicy },
+    DynResult,
+};
 use std::{ collections::HashMap, io::Write, path::PathBuf };
 use tinyjson::*;
 
@@ -26,9 +33,97 @@ pub fn save_config(app_state: &AppState) {
             data_hashmap.insert("auto_restart".into(), JsonValue::Boolean(app_state.auto_restart));
             data_hashmap.insert("fast_mode".into(), JsonValue::Boolean(app_state.fast_mode));
             data_hashmap.insert("debug_mode".into(), JsonValue::Boolean(app_state.debug_mode));
+            data_hashmap.insert("offline_mode".into(), JsonValue::Boolean(app_state.offline_mode));
             data_hashmap.insert(
-                "kill_hiper_when_start".into(),
-                JsonValue::Boolean(app_state.kill_hiper_when_start)
+                "existing_process_policy".into(),
+                JsonValue::String(app_state.existing_process_policy.id().to_owned())
+            );
+            data_hashmap.insert(
+                "extra_args".into(),
+                JsonValue::String(app_state.extra_args.to_owned())
+            );
+            data_hashmap.insert(
+                "hiper_path".into(),
+                JsonValue::String(app_state.hiper_path.to_owned())
+            );
+            data_hashmap.insert(
+                "restart_delay_secs".into(),
+                JsonValue::Number(app_state.restart_delay_secs as f64)
+            );
+            data_hashmap.insert(
+                "restart_max_delay_secs".into(),
+                JsonValue::Number(app_state.restart_max_delay_secs as f64)
+            );
+            data_hashmap.insert(
+                "restart_max_retries".into(),
+                JsonValue::Number(app_state.restart_max_retries as f64)
+            );
+            data_hashmap.insert(
+                "restart_stable_secs".into(),
+                JsonValue::Number(app_state.restart_stable_secs as f64)
+            );
+            data_hashmap.insert(
+                "stop_timeout_secs".into(),
+                JsonValue::Number(app_state.stop_timeout_secs as f64)
+            );
+            data_hashmap.insert(
+                "join_timeout_secs".into(),
+                JsonValue::Number(app_state.join_timeout_secs as f64)
+            );
+            data_hashmap.insert(
+                "log_history_count".into(),
+                JsonValue::Number(app_state.log_history_count as f64)
+            );
+            data_hashmap.insert(
+                "log_history_max_mb".into(),
+                JsonValue::Number(app_state.log_history_max_mb as f64)
+            );
+
+            data_hashmap.insert(
+                "update_policy".into(),
+                JsonValue::String(app_state.update_policy.id().to_owned())
+            );
+            data_hashmap.insert(
+                "update_channel".into(),
+                JsonValue::String(app_state.update_channel.id().to_owned())
+            );
+            data_hashmap.insert(
+                "pinned_version".into(),
+                JsonValue::String(app_state.pinned_version.to_owned())
+            );
+            data_hashmap.insert(
+                "update_check_interval_hours".into(),
+                JsonValue::Number(app_state.update_check_interval_hours as f64)
+            );
+            data_hashmap.insert(
+                "last_update_check".into(),
+                JsonValue::Number(app_state.last_update_check as f64)
+            );
+            data_hashmap.insert("bridge_auto_update".into(), JsonValue::Boolean(app_state.bridge_auto_update));
+            data_hashmap.insert("proxy".into(), JsonValue::String(app_state.proxy.to_owned()));
+            data_hashmap.insert("no_proxy".into(), JsonValue::String(app_state.no_proxy.to_owned()));
+            data_hashmap.insert(
+                "mirrors".into(),
+                JsonValue::Array(
+                    app_state.mirrors
+                        .iter()
+                        .map(|x| x.to_json())
+                        .collect()
+                )
+            );
+            data_hashmap.insert(
+                "last_mirror".into(),
+                JsonValue::String(app_state.last_mirror.to_owned())
+            );
+
+            data_hashmap.insert(
+                "networks".into(),
+                JsonValue::Array(
+                    app_state.networks
+                        .iter()
+                        .map(|x| x.to_json())
+                        .collect()
+                )
             );
 
             let data = JsonValue::Object(data_hashmap);
@@ -93,12 +188,155 @@ pub fn load_config(app_state: &mut AppState) {
                     {
                         app_state.debug_mode = debug_mode;
                     }
+                    if
+                        let Some(offline_mode) = data
+                            .get("offline_mode")
+                            .map(|x| x.get::<bool>().copied().unwrap_or(false))
+                    {
+                        app_state.offline_mode = offline_mode;
+                    }
+                    // 兼容旧版本的单进程模式设置
                     if
                         let Some(kill_hiper_when_start) = data
                             .get("kill_hiper_when_start")
                             .map(|x| x.get::<bool>().copied().unwrap_or(true))
                     {
-                        app_state.kill_hiper_when_start = kill_hiper_when_start;
+                        app_state.existing_process_policy = if kill_hiper_when_start {
+                            ExistingProcessPolicy::StopOwned
+                        } else {
+                            ExistingProcessPolicy::Ignore
+                        };
+                    }
+                    if
+                        let Some(Some(policy)) = data
+                            .get("existing_process_policy")
+                            .map(|x| x.get::<String>().and_then(|x| ExistingProcessPolicy::from_id(x)))
+                    {
+                        app_state.existing_process_policy = policy;
+                    }
+                    if let Some(Some(extra_args)) = data.get("extra_args").map(|x| x.get::<String>()) {
+                        app_state.extra_args = extra_args.to_owned();
+                    }
+                    if let Some(Some(hiper_path)) = data.get("hiper_path").map(|x| x.get::<String>()) {
+                        app_state.hiper_path = hiper_path.to_owned();
+                    }
+                    if let Some(Some(delay)) = data.get("restart_delay_secs").map(|x| x.get::<f64>()) {
+                        app_state.restart_delay_secs = *delay as u64;
+                    }
+                    if
+                        let Some(Some(max_delay)) = data
+                            .get("restart_max_delay_secs")
+                            .map(|x| x.get::<f64>())
+                    {
+                        app_state.restart_max_delay_secs = *max_delay as u64;
+                    }
+                    if
+                        let Some(Some(max_retries)) = data
+                            .get("restart_max_retries")
+                            .map(|x| x.get::<f64>())
+                    {
+                        app_state.restart_max_retries = *max_retries as u32;
+                    }
+                    if
+                        let Some(Some(stable)) = data
+                            .get("restart_stable_secs")
+                            .map(|x| x.get::<f64>())
+                    {
+                        app_state.restart_stable_secs = *stable as u64;
+                    }
+                    if
+                        let Some(Some(stop_timeout)) = data
+                            .get("stop_timeout_secs")
+                            .map(|x| x.get::<f64>())
+                    {
+                        app_state.stop_timeout_secs = *stop_timeout as u64;
+                    }
+                    if
+                        let Some(Some(join_timeout)) = data
+                            .get("join_timeout_secs")
+                            .map(|x| x.get::<f64>())
+                    {
+                        app_state.join_timeout_secs = *join_timeout as u64;
+                    }
+                    if
+                        let Some(Some(log_history_count)) = data
+                            .get("log_history_count")
+                            .map(|x| x.get::<f64>())
+                    {
+                        app_state.log_history_count = *log_history_count as u64;
+                    }
+                    if
+                        let Some(Some(log_history_max_mb)) = data
+                            .get("log_history_max_mb")
+                            .map(|x| x.get::<f64>())
+                    {
+                        app_state.log_history_max_mb = *log_history_max_mb as u64;
+                    }
+                    if
+                        let Some(Some(policy)) = data
+                            .get("update_policy")
+                            .map(|x| x.get::<String>().and_then(|x| UpdatePolicy::from_id(x)))
+                    {
+                        app_state.update_policy = policy;
+                    }
+                    if
+                        let Some(Some(channel)) = data
+                            .get("update_channel")
+                            .map(|x| x.get::<String>().and_then(|x| UpdateChannel::from_id(x)))
+                    {
+                        app_state.update_channel = channel;
+                    }
+                    if let Some(Some(pinned_version)) = data.get("pinned_version").map(|x| x.get::<String>()) {
+                        app_state.pinned_version = pinned_version.to_owned();
+                    }
+                    if
+                        let Some(Some(interval)) = data
+                            .get("update_check_interval_hours")
+                            .map(|x| x.get::<f64>())
+                    {
+                        app_state.update_check_interval_hours = *interval as u64;
+                    }
+                    if
+                        let Some(Some(last_check)) = data
+                            .get("last_update_check")
+                            .map(|x| x.get::<f64>())
+                    {
+                        app_state.last_update_check = *last_check as i64;
+                    }
+                    if
+                        let Some(bridge_auto_update) = data
+                            .get("bridge_auto_update")
+                            .map(|x| x.get::<bool>().copied().unwrap_or(true))
+                    {
+                        app_state.bridge_auto_update = bridge_auto_update;
+                    }
+                    if let Some(Some(proxy)) = data.get("proxy").map(|x| x.get::<String>()) {
+                        app_state.proxy = proxy.to_owned();
+                    }
+                    if let Some(Some(no_proxy)) = data.get("no_proxy").map(|x| x.get::<String>()) {
+                        app_state.no_proxy = no_proxy.to_owned();
+                    }
+                    if let Some(Some(mirrors)) = data.get("mirrors").map(|x| x.get::<Vec<JsonValue>>()) {
+                        let mirrors: Vec<Mirror> = mirrors.iter().filter_map(Mirror::from_json).collect();
+                        if !mirrors.is_empty() {
+                            app_state.mirrors = mirrors.into();
+                        }
+                    }
+                    if let Some(Some(last_mirror)) = data.get("last_mirror").map(|x| x.get::<String>()) {
+                        app_state.last_mirror = last_mirror.to_owned();
+                    }
+                    if let Some(Some(networks)) = data.get("networks").map(|x| x.get::<Vec<JsonValue>>()) {
+                        app_state.networks = druid::im::Vector::new();
+                        for network in networks {
+                            let next_id = app_state.next_network_id();
+                            if let Some(mut network) = NetworkState::from_json(network, next_id) {
+                                // 编号重复时重新分配，避免两个网络共用同一个工作目录
+                                if app_state.network(network.id).is_some() {
+                                    network.id = next_id;
+                                }
+                                app_state.networks.push_back(network);
+                            }
+                        }
                     }
                 }
             }
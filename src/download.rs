@@ -0,0 +1,255 @@
+//! 流式下载
+//!
+//! 较大的文件（HiPer 程序、WinTUN 和 WinTAP 安装程序）会边下载边写入磁盘上的临时文件，
+//! 中断后再次下载时会通过 HTTP Range 请求从已下载的位置继续，并定时报告下载进度。
+//! `file://` 地址会直接从本地文件复制，便于使用本地目录作为镜像
+
+use std::{
+    fmt::Display,
+    fs::{ File, OpenOptions },
+    io::{ Read, Seek, SeekFrom, Write },
+    path::Path,
+    time::{ Duration, Instant },
+};
+
+use anyhow::Context;
+
+use crate::DynResult;
+
+/// 建立连接的超时时间
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// 两次读取到数据之间的最长等待时间
+pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// 报告下载进度的最短间隔
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// 一次下载的进度
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DownloadProgress {
+    /// 已经下载的字节数，包括之前中断时已经下载的部分
+    pub downloaded: u64,
+    /// 文件的总字节数，服务器没有提供时为 `None`
+    pub total: Option<u64>,
+    /// 本次下载的平均速度（字节每秒）
+    pub speed: f64,
+}
+
+/// 将字节数格式化为便于阅读的大小
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} {}", size, UNITS[unit]) } else { format!("{:.1} {}", size, UNITS[unit]) }
+}
+
+impl Display for DownloadProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.total {
+            Some(total) if total > 0 =>
+                write!(
+                    f,
+                    "{} / {}，{}%，{}/s",
+                    format_size(self.downloaded),
+                    format_size(total),
+                    (self.downloaded * 100) / total,
+                    format_size(self.speed as u64)
+                ),
+            _ => write!(f, "{}，{}/s", format_size(self.downloaded), format_size(self.speed as u64)),
+        }
+    }
+}
+
+/// 通过 HTTP 下载，使用 Range 请求继续之前中断的下载
+pub fn download_over_http(
+    agent: &ureq::Agent,
+    url: &str,
+    part_path: &Path,
+    on_progress: &mut dyn FnMut(&DownloadProgress) -> DynResult
+) -> DynResult {
+    let existing = part_len(part_path);
+
+    let mut request = agent.get(url);
+    if existing > 0 {
+        println!("Resuming download of {} from {} bytes", url, existing);
+        request = request.set("Range", &format!("bytes={}-", existing));
+    }
+    let res = match request.call() {
+        Ok(res) => res,
+        // 已下载的部分已经是完整的文件，是否正确交给调用方校验
+        Err(ureq::Error::Status(416, _)) if existing > 0 => {
+            return Ok(());
+        }
+        Err(err) => {
+            return Err(err.into());
+        }
+    };
+
+    let resumed_from = if res.status() == 206 { Some(existing) } else { None };
+    let total = res
+        .header("Content-Length")
+        .and_then(|x| x.parse::<u64>().ok())
+        .map(|x| x + resumed_from.unwrap_or(0));
+    write_part(res.into_reader(), part_path, resumed_from, total, on_progress)
+}
+
+/// 从本地文件“下载”，用于 `file://` 地址，同样会从已下载的位置继续
+pub fn download_local_file(
+    source: &Path,
+    part_path: &Path,
+    on_progress: &mut dyn FnMut(&DownloadProgress) -> DynResult
+) -> DynResult {
+    let mut file = File::open(source).with_context(|| format!("无法打开 {}", source.display()))?;
+    let total = file.metadata().context("无法读取文件大小")?.len();
+    let existing = part_len(part_path);
+    let resumed_from = if existing > 0 && existing <= total {
+        file.seek(SeekFrom::Start(existing)).context("无法继续下载")?;
+        Some(existing)
+    } else {
+        None
+    };
+    write_part(file, part_path, resumed_from, Some(total), on_progress)
+}
+
+fn part_len(part_path: &Path) -> u64 {
+    part_path
+        .metadata()
+        .map(|x| x.len())
+        .unwrap_or(0)
+}
+
+/// 将 `reader` 的内容写入 `part_path`，`resumed_from` 为继续下载时已有的字节数
+fn write_part(
+    mut reader: impl Read,
+    part_path: &Path,
+    resumed_from: Option<u64>,
+    total: Option<u64>,
+    on_progress: &mut dyn FnMut(&DownloadProgress) -> DynResult
+) -> DynResult {
+    let resumed = resumed_from.is_some();
+    let existing = resumed_from.unwrap_or(0);
+    let mut downloaded = existing;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(part_path)
+        .context("无法创建下载临时文件")?;
+
+    let start = Instant::now();
+    let mut last_report = start;
+    let mut buf = vec![0; 64 * 1024];
+    let progress = |downloaded: u64| DownloadProgress {
+        downloaded,
+        total,
+        speed: ((downloaded - existing) as f64) / start.elapsed().as_secs_f64().max(0.001),
+    };
+    on_progress(&progress(downloaded))?;
+    loop {
+        let len = reader.read(&mut buf).context("下载时连接中断")?;
+        if len == 0 {
+            break;
+        }
+        file.write_all(&buf[..len]).context("无法写入下载临时文件")?;
+        downloaded += len as u64;
+        if last_report.elapsed() >= PROGRESS_INTERVAL {
+            last_report = Instant::now();
+            on_progress(&progress(downloaded))?;
+        }
+    }
+    file.sync_all().context("无法写入下载临时文件")?;
+    on_progress(&progress(downloaded))?;
+
+    if let Some(total) = total {
+        if downloaded < total {
+            anyhow::bail!("下载不完整（{} / {}）", downloaded, total);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{ http::{ HttpClient, ProxyConfig, UreqClient }, test_server::TestServer };
+
+    const BODY: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    fn client() -> UreqClient {
+        UreqClient::with_proxy(ProxyConfig {
+            url: "".into(),
+            no_proxy: "*".into(),
+        })
+    }
+
+    fn part_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("hiper-bridge-test-{}-{}.download", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn downloads_whole_file_and_reports_progress() {
+        let server = TestServer::start(&[("/hiper", BODY)]);
+        let part = part_path("whole");
+        let mut last = None;
+        client()
+            .download(&format!("{}/hiper", server.url()), &part, &mut |x| {
+                last = Some(*x);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(std::fs::read(&part).unwrap(), BODY);
+        let last = last.unwrap();
+        assert_eq!(last.downloaded, BODY.len() as u64);
+        assert_eq!(last.total, Some(BODY.len() as u64));
+        assert_eq!(server.requests()[0].range, None);
+        let _ = std::fs::remove_file(part);
+    }
+
+    #[test]
+    fn resumes_partial_download_with_range() {
+        let server = TestServer::start(&[("/hiper", BODY)]);
+        let part = part_path("resume");
+        std::fs::write(&part, &BODY[..10]).unwrap();
+        client().download(&format!("{}/hiper", server.url()), &part, &mut |_| Ok(())).unwrap();
+        assert_eq!(std::fs::read(&part).unwrap(), BODY);
+        assert_eq!(server.requests()[0].range.as_deref(), Some("bytes=10-"));
+        let _ = std::fs::remove_file(part);
+    }
+
+    #[test]
+    fn range_not_satisfiable_keeps_complete_part_file() {
+        let server = TestServer::start(&[("/hiper", BODY)]);
+        let part = part_path("complete");
+        std::fs::write(&part, BODY).unwrap();
+        client().download(&format!("{}/hiper", server.url()), &part, &mut |_| Ok(())).unwrap();
+        assert_eq!(std::fs::read(&part).unwrap(), BODY);
+        assert_eq!(server.requests()[0].range, Some(format!("bytes={}-", BODY.len())));
+        let _ = std::fs::remove_file(part);
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        let server = TestServer::start(&[]);
+        let part = part_path("missing");
+        assert!(client().download(&format!("{}/hiper", server.url()), &part, &mut |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn progress_error_stops_download_and_keeps_part_file() {
+        let server = TestServer::start(&[("/hiper", BODY)]);
+        let part = part_path("cancel");
+        let result = client().download(&format!("{}/hiper", server.url()), &part, &mut |_| {
+            anyhow::bail!("cancelled")
+        });
+        assert!(result.is_err());
+        assert!(part.exists());
+        let _ = std::fs::remove_file(part);
+    }
+}
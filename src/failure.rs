@@ -0,0 +1,168 @@
+//! HiPer 启动和运行失败的原因
+//!
+//! 从 HiPer 的日志和退出状态中识别出失败原因，用于向用户显示对应的提示，
+//! 并以稳定的标识传递给插件
+
+use std::{ fmt::Display, process::ExitStatus };
+
+use crate::{ log_parser::LogEvent, process::describe_exit_status };
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HiperFailure {
+    /// 凭证已过期
+    CertificateExpired,
+    /// 无法监听服务端口，一般是端口被占用
+    UdpListenerUnavailable,
+    /// 无法获取 TUN/TAP 设备，一般是设备被其它 HiPer 占用
+    TunDeviceUnavailable,
+    /// HiPer 在入网前就退出了，附带退出状态的描述
+    Exited(String),
+    /// HiPer 没有获取到可用的 IP 地址
+    NoAddress,
+    /// HiPer 没有在入网超时内获取到 IP 地址，附带超时秒数
+    JoinTimeout(u64),
+}
+
+impl HiperFailure {
+    /// 从一条错误日志中识别失败原因，无法识别时返回 `None`
+    ///
+    /// HiPer 会把失败的步骤写在消息中、把具体原因写在错误信息中，因此两者都会被检查
+    pub fn from_log_event(event: &LogEvent) -> Option<Self> {
+        if event.level != "error" && event.level != "fatal" {
+            return None;
+        }
+        let text = format!("{}\n{}", event.message, event.error).to_ascii_lowercase();
+        if text.contains("certificate") && text.contains("expired") {
+            Some(Self::CertificateExpired)
+        } else if text.contains("failed to open udp listener") {
+            Some(Self::UdpListenerUnavailable)
+        } else if text.contains("failed to get a tun/tap device") {
+            Some(Self::TunDeviceUnavailable)
+        } else {
+            None
+        }
+    }
+
+    /// 根据 HiPer 的退出状态生成失败原因
+    pub fn from_exit_status(status: Option<&ExitStatus>) -> Self {
+        match status {
+            Some(status) if !status.success() => Self::Exited(describe_exit_status(status)),
+            _ => Self::NoAddress,
+        }
+    }
+
+    /// 失败是否可能由 HiPer 程序本身导致，凭证和端口等环境问题不会因为更换程序而解决
+    pub fn may_be_caused_by_binary(&self) -> bool {
+        matches!(self, Self::Exited(_) | Self::NoAddress | Self::JoinTimeout(_))
+    }
+
+    /// 传递给插件的稳定标识
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::CertificateExpired => "certificate-expired",
+            Self::UdpListenerUnavailable => "udp-listener-unavailable",
+            Self::TunDeviceUnavailable => "tun-device-unavailable",
+            Self::Exited(_) => "exited",
+            Self::NoAddress => "no-address",
+            Self::JoinTimeout(_) => "join-timeout",
+        }
+    }
+
+    /// 显示给用户的提示信息
+    pub fn message(&self) -> String {
+        match self {
+            Self::CertificateExpired => "警告：凭证已过期！请使用新的凭证密钥重试！".into(),
+            Self::UdpListenerUnavailable => "错误：HiPer 无法监听服务端口，请确认端口占用情况！".into(),
+            Self::TunDeviceUnavailable =>
+                "错误：无法获取 TUN/TAP 设备！这应该是你多开了 HiPer 导致设备被占用了".into(),
+            Self::Exited(description) =>
+                format!("错误：HiPer 启动失败（{}）！请检查 latest.log 日志文件确认问题！", description),
+            Self::NoAddress => "错误：HiPer 入网失败！请检查凭证密钥是否填写正确！".into(),
+            Self::JoinTimeout(secs) =>
+                format!("错误：HiPer 在 {} 秒内没有完成入网，已停止启动！请检查网络环境后重试！", secs),
+        }
+    }
+
+    /// 生成传递给插件的环境变量
+    pub fn env(&self) -> (&'static str, String) {
+        ("HIPER_FAILURE", self.id().to_owned())
+    }
+}
+
+impl Display for HiperFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// HiPer 输出的日志行和识别出的失败原因
+    const FAILURE_FIXTURES: &[(&str, Option<HiperFailure>)] = &[
+        // 凭证过期
+        (
+            r#"{"error":"Hiper certificate for this point is expired","level":"error","msg":"Failed to load certificate from config","time":"2022-10-01T12:00:00+08:00"}"#,
+            Some(HiperFailure::CertificateExpired),
+        ),
+        (
+            r#"time="2022-10-01T12:00:00+08:00" level=error msg="Hiper certificate for this point is expired""#,
+            Some(HiperFailure::CertificateExpired),
+        ),
+        (
+            r#"FATA[0000] Failed to load certificate from config       error="nebula certificate for this host is EXPIRED""#,
+            Some(HiperFailure::CertificateExpired),
+        ),
+        // 无法监听服务端口
+        (
+            r#"{"error":"listen udp4 0.0.0.0:4242: bind: address already in use","level":"error","msg":"Failed to open udp listener","time":"2022-10-01T12:00:00+08:00"}"#,
+            Some(HiperFailure::UdpListenerUnavailable),
+        ),
+        (
+            r#"ERRO[0000] Failed to open udp listener                  error="listen udp4 0.0.0.0:4242: bind: address already in use""#,
+            Some(HiperFailure::UdpListenerUnavailable),
+        ),
+        (r#"{"error":"Failed to open udp listener","level":"error","msg":"Failed to start"}"#, Some(HiperFailure::UdpListenerUnavailable)),
+        // 无法获取 TUN/TAP 设备
+        (
+            r#"time="2022-10-01T12:00:00+08:00" level=error msg="Failed to get a tun/tap device" error="operation not permitted: \"/dev/net/tun\"""#,
+            Some(HiperFailure::TunDeviceUnavailable),
+        ),
+        (
+            r#"{"error":"Cannot create a file when that file already exists.","level":"fatal","msg":"Failed to get a tun/tap device"}"#,
+            Some(HiperFailure::TunDeviceUnavailable),
+        ),
+        // 无法识别的错误
+        (
+            r#"ERRO[0003] Failed to send handshake message   error="sendto: network is unreachable" udpAddr="1.2.3.4:4242""#,
+            None,
+        ),
+        (r#"{"error":"unexpected EOF","level":"error","msg":"Failed to read config"}"#, None),
+        // 不是错误等级的日志即使包含相同的文字也不会被识别
+        (r#"{"level":"info","msg":"Failed to open udp listener, retrying"}"#, None),
+        (
+            r#"WARN[0000] Certificate will expire soon                  expiry="2022-10-08 12:00:00 +0800 CST""#,
+            None,
+        ),
+        ("Failed to get a tun/tap device", None),
+        ("", None),
+    ];
+
+    #[test]
+    fn from_log_event_fixtures() {
+        for (line, expected) in FAILURE_FIXTURES {
+            assert_eq!(HiperFailure::from_log_event(&LogEvent::parse(line)), *expected, "{}", line);
+        }
+    }
+
+    #[test]
+    fn only_binary_failures_trigger_rollback() {
+        assert!(HiperFailure::Exited("退出码 1".into()).may_be_caused_by_binary());
+        assert!(HiperFailure::NoAddress.may_be_caused_by_binary());
+        assert!(HiperFailure::JoinTimeout(60).may_be_caused_by_binary());
+        assert!(!HiperFailure::CertificateExpired.may_be_caused_by_binary());
+        assert!(!HiperFailure::UdpListenerUnavailable.may_be_caused_by_binary());
+        assert!(!HiperFailure::TunDeviceUnavailable.may_be_caused_by_binary());
+    }
+}
@@ -1,29 +1,56 @@
 use std::{
     fs::OpenOptions,
-    io::{ BufRead, BufReader, Write },
-    path::PathBuf,
+    io::{ BufRead, BufReader, Read, Write },
+    path::{ Path, PathBuf },
     process::{ Command, Stdio },
-    sync::{ atomic::{ AtomicBool, AtomicU32 }, Mutex },
+    sync::{ mpsc::Sender, Arc, Mutex },
+    time::{ Duration, Instant },
 };
 
-use crate::{ plugin, ui::*, utils::write_file_safe, DynResult };
+use crate::{
+    download::DownloadProgress,
+    failure::HiperFailure,
+    hiper_version::{ self, ReleaseNotes },
+    integrity::{ sha1_hex, VerifiedDownloader },
+    instance::{ self, HiperInstance, InstanceId },
+    launch_options::HiperLaunchOptions,
+    log_history,
+    log_parser::LogEvent,
+    process::{
+        self,
+        describe_exit_status,
+        exit_status_env,
+        ExistingProcessPolicy,
+        HiperProcess,
+        PidFile,
+    },
+    stage::{ ConnectionState, LaunchCancelled, LaunchStage },
+    supervisor::{ self, RestartPolicy },
+    ui::*,
+    update::UpdatePolicy,
+    utils::{ install_file, path_with_suffix },
+    DynResult,
+};
 use anyhow::Context;
 use druid::{ ExtEventSink, Target };
 #[cfg(windows)]
-use windows::Win32::System::{
-    ProcessStatus::{ K32EnumDeviceDrivers, K32GetDeviceDriverBaseNameW },
-    Threading::{
-        OpenProcess,
-        TerminateProcess,
-        WaitForSingleObject,
-        PROCESS_SYNCHRONIZE,
-        PROCESS_TERMINATE,
-    },
-};
+use windows::Win32::System::ProcessStatus::{ K32EnumDeviceDrivers, K32GetDeviceDriverBaseNameW };
 
-static HIPER_PROCESS: AtomicU32 = AtomicU32::new(0);
-static HAS_UPDATED: AtomicBool = AtomicBool::new(false);
-static SPAWNED_PROCESSES: Mutex<Option<Vec<u32>>> = Mutex::new(None);
+/// HiPer 程序在镜像和安装目录中的文件名
+#[cfg(windows)]
+pub const HIPER_FILE_NAME: &str = "hiper.exe";
+/// HiPer 程序在镜像和安装目录中的文件名
+#[cfg(not(windows))]
+pub const HIPER_FILE_NAME: &str = "hiper";
+/// WinTUN 在镜像和安装目录中的文件名
+pub const WINTUN_FILE_NAME: &str = "wintun.dll";
+/// WinTAP 安装程序在镜像中的文件名
+pub const TAP_INSTALLER_DOWNLOAD_NAME: &str = "tap-windows-9.21.2.exe";
+/// WinTAP 安装程序在安装目录中的文件名
+pub const TAP_INSTALLER_FILE_NAME: &str = "tap-windows.exe";
+
+/// 本次运行中因无法入网而被回滚的 HiPer 版本摘要，不会再次更新到这些版本
+static ROLLED_BACK_HASHES: Mutex<Vec<String>> = Mutex::new(Vec::new());
 
 #[cfg(windows)]
 fn check_tap_installed() -> bool {
@@ -56,64 +83,163 @@ fn check_tap_installed() -> bool {
     false
 }
 
-pub fn get_log_file_path() -> DynResult<PathBuf> {
-    use path_absolutize::*;
-    Ok(
-        get_hiper_dir()?
-            .join("latest.log")
-            .absolutize()
-            .map(|x| x.to_path_buf())?
-    )
-}
-
-pub fn run_hiper_in_thread(
+/// 启动指定实例的 HiPer，会先使用新的策略重置该实例的崩溃重启计数
+pub fn start_hiper(
     ctx: ExtEventSink,
-    token: String,
-    use_tun: bool,
-    use_tcp: bool,
-    use_igmp: bool,
-    fast_mode: bool,
-    debug_mode: bool,
-    kill_hiper_when_start: bool
+    id: InstanceId,
+    options: HiperLaunchOptions,
+    policy: RestartPolicy
 ) {
+    instance::get(id).supervisor().reset(policy);
+    run_hiper_in_thread(ctx, id, options);
+}
+
+pub fn run_hiper_in_thread(ctx: ExtEventSink, id: InstanceId, options: HiperLaunchOptions) {
     std::thread::spawn(move || {
-        let _ = ctx.submit_command(SET_DISABLED, true, Target::Auto);
-        if kill_hiper_when_start {
-            #[cfg(windows)]
-            {
-                use std::os::windows::process::CommandExt;
-                let _ = std::process::Command
-                    ::new("taskkill.exe")
-                    .arg("/F")
-                    .arg("/IM")
-                    .arg("hiper.exe")
-                    .creation_flags(0x08000000)
-                    .status();
-            }
-            #[cfg(any(target_os = "linux", target_os = "macos"))]
-            {
-                let _ = std::process::Command::new("sudo").arg("killall").arg("hiper").status();
-            }
+        let instance = instance::get(id);
+        let _ = ctx.submit_command(SET_DISABLED, (id, true), Target::Auto);
+        if handle_existing_processes(&ctx, &instance, &options) {
+            let _ = ctx.submit_command(SET_DISABLED, (id, false), Target::Auto);
+            return;
         }
 
-        match run_hiper(ctx.to_owned(), token, use_tun, use_tcp, use_igmp, fast_mode, debug_mode) {
+        let result = run_hiper(ctx.to_owned(), instance.to_owned(), options.to_owned());
+        let cancelled = matches!(&result, Err(e) if e.is::<LaunchCancelled>());
+        match result {
             Ok(_) => {
                 println!("Launched!");
             }
             Err(e) => {
-                println!("Failed to launch! {:?}", e);
-                let _ = ctx.submit_command(
-                    SET_WARNING,
-                    format!("启动时发生错误：{:?}", e),
-                    Target::Auto
-                );
-                let _ = ctx.submit_command(SET_START_TEXT, "启动", Target::Auto);
+                let warning = if e.is::<LaunchCancelled>() {
+                    println!("{} 的 HiPer 启动已取消", instance.name());
+                    e.to_string()
+                } else {
+                    println!("Failed to launch! {:?}", e);
+                    format!("启动时发生错误：{:?}", e)
+                };
+                let _ = ctx.submit_command(SET_WARNING, (id, warning), Target::Auto);
+                let _ = ctx.submit_command(SET_IP, (id, "".to_string()), Target::Auto);
+                instance.set_stage(&ctx, ConnectionState::new(LaunchStage::Idle));
+            }
+        }
+        // 守护程序发起的重启在入网前失败同样视为一次崩溃，按退避策略继续重启直到判定为崩溃循环
+        let restart_generation = {
+            let mut supervisor = instance.supervisor();
+            Some(supervisor.generation()).filter(|_| supervisor.take_restarting() && !cancelled)
+        };
+        if let Some(generation) = restart_generation {
+            supervisor::schedule_restart(ctx.to_owned(), id, options, generation);
+        }
+        let _ = ctx.submit_command(SET_DISABLED, (id, false), Target::Auto);
+    });
+}
+
+/// 根据设置处理实例上次启动且仍在运行的 HiPer 进程，接管了该进程时返回 `true`
+///
+/// 并非由该实例启动的 HiPer 进程不会被处理
+fn handle_existing_processes(
+    ctx: &ExtEventSink,
+    instance: &Arc<HiperInstance>,
+    options: &HiperLaunchOptions
+) -> bool {
+    // 本次运行中启动的进程会在启动新进程时被停止
+    if instance.is_running() {
+        return false;
+    }
+    let pid_file_path = match instance.pid_file_path() {
+        Ok(path) => path,
+        Err(_) => {
+            return false;
+        }
+    };
+    let owned = process
+        ::read_pid_file(&pid_file_path)
+        .filter(|x| !instance::owns_pid(x.pid) && process::is_hiper_process(x.pid, &x.binary));
+    let foreign = process
+        ::find_hiper_processes()
+        .into_iter()
+        .filter(|pid| !instance::owns_pid(*pid))
+        .filter(|pid| owned.as_ref().map(|x| x.pid != *pid).unwrap_or(true))
+        .map(|pid| pid.to_string())
+        .collect::<Vec<_>>();
+    if !foreign.is_empty() {
+        crate::log_buffer::warn(format!("检测到其它 HiPer 进程正在运行，将不做处理：{}", foreign.join(", ")));
+    }
+
+    if let Some(owned) = owned {
+        match options.existing_process {
+            // 没有记录 IP 地址说明上次启动的进程没有成功入网，接管也没有意义
+            ExistingProcessPolicy::Adopt if !owned.ip.is_empty() => {
+                adopt_process(ctx, instance, owned, options);
+                return true;
             }
+            ExistingProcessPolicy::Adopt | ExistingProcessPolicy::StopOwned => {
+                println!("Stopping previously launched hiper process {}", owned.pid);
+                HiperProcess::adopt(owned.pid, owned.binary.to_owned(), options.stop_timeout).stop();
+                process::remove_pid_file(&pid_file_path, owned.pid);
+            }
+            ExistingProcessPolicy::Ignore => {}
+        }
+    }
+    false
+}
+
+/// 接管实例上次启动且仍在运行的 HiPer 进程，并在后台等待其退出
+fn adopt_process(
+    ctx: &ExtEventSink,
+    instance: &Arc<HiperInstance>,
+    pid_file: PidFile,
+    options: &HiperLaunchOptions
+) {
+    println!("Adopting hiper process {} for {}", pid_file.pid, instance.name());
+    let id = instance.id();
+    let process = Arc::new(
+        HiperProcess::adopt(pid_file.pid, pid_file.binary.to_owned(), options.stop_timeout)
+    );
+    instance.register_process(process.clone());
+    instance.supervisor().mark_started();
+
+    let _ = ctx.submit_command(SET_IP, (id, pid_file.ip), Target::Auto);
+    instance.set_stage(ctx, ConnectionState::new(LaunchStage::Running));
+    let _ = ctx.submit_command(
+        SET_WARNING,
+        (id, format!("已接管上次启动且仍在运行的 HiPer 进程（PID {}）", pid_file.pid)),
+        Target::Auto
+    );
+
+    let ctx = ctx.to_owned();
+    let instance = instance.to_owned();
+    std::thread::spawn(move || {
+        process.wait_timeout(None);
+        instance.unregister_process(&process);
+        if let Ok(path) = instance.pid_file_path() {
+            process::remove_pid_file(&path, process.id());
+        }
+        crate::log_buffer::warn(format!("{} 已接管的 HiPer 已退出！", instance.name()));
+        instance.dispatch_event("stopped", &[]);
+
+        if !process.is_stop_requested() {
+            let generation = leave_running(&ctx, &instance);
+            let _ = ctx.submit_command(
+                SET_WARNING,
+                (id, "警告：已接管的 HiPer 服务程序已意外退出！".to_string()),
+                Target::Auto
+            );
+            instance.dispatch_event("crashed", &[]);
+            let _ = ctx.submit_command(REQUEST_RESTART, (id, generation), Target::Auto);
         }
-        let _ = ctx.submit_command(SET_DISABLED, false, Target::Auto);
     });
 }
 
+/// 已入网的 HiPer 不是因为 HiPer Bridge 主动停止而退出时调用，将实例切换回未启动并清除 IP 地址
+///
+/// 返回此时守护程序的代数，用于请求重启
+fn leave_running(ctx: &ExtEventSink, instance: &HiperInstance) -> u64 {
+    let _ = ctx.submit_command(SET_IP, (instance.id(), "".to_string()), Target::Auto);
+    instance.set_stage(ctx, ConnectionState::new(LaunchStage::Idle));
+    instance.supervisor().generation()
+}
+
 pub fn get_hiper_dir() -> DynResult<PathBuf> {
     #[cfg(windows)]
     {
@@ -141,33 +267,52 @@ pub fn get_hiper_dir() -> DynResult<PathBuf> {
 
 pub fn run_hiper(
     ctx: ExtEventSink,
-    token: String,
-    use_tun: bool,
-    use_tcp: bool,
-    use_igmp: bool,
-    fast_mode: bool,
-    debug_mode: bool
+    instance: Arc<HiperInstance>,
+    options: HiperLaunchOptions
 ) -> DynResult {
-    println!("Launching hiper using token {}", token);
+    let id = instance.id();
+    println!("Launching hiper for {} using token {}", instance.name(), options.token);
+    instance.begin_launch();
+    crate::http::set_proxy(options.proxy.to_owned());
 
-    crate::plugin::update_plugins(ctx.to_owned());
+    if options.offline_mode {
+        crate::log_buffer::info("离线模式，跳过插件更新检查");
+    } else {
+        crate::plugin::update_plugins(ctx.to_owned(), &instance);
+    }
 
-    let has_token = !token.is_empty();
-    let _ = ctx.submit_command(SET_START_TEXT, "正在检查所需文件", Target::Auto);
-    let _ = ctx.submit_command(SET_WARNING, "".to_string(), Target::Auto);
+    let has_token = options.has_token();
+    let use_tun = options.use_tun;
+    #[cfg(all(windows, not(debug_assertions)))]
+    let debug_mode = options.debug_mode && instance.is_primary();
+    let stop_timeout = options.stop_timeout;
+    let join_timeout = options.join_timeout;
+    #[cfg(windows)]
+    let mut driver_downloader = VerifiedDownloader::new(options.mirrors.to_owned(), "");
+    enter_stage(&ctx, &instance, LaunchStage::CheckingFiles)?;
+    let _ = ctx.submit_command(SET_WARNING, (id, "".to_string()), Target::Auto);
 
     let hiper_dir_path = get_hiper_dir()?;
+    let working_dir = instance.working_dir()?;
+    let pid_file_path = instance.pid_file_path()?;
 
     #[cfg(windows)]
-    let tap_path = hiper_dir_path.join("tap-windows.exe");
-    let wintun_path = hiper_dir_path.join("wintun.dll");
-    let wintun_disabled_path = hiper_dir_path.join("wintun.dll.disabled");
-    #[cfg(windows)]
-    let hiper_path = hiper_dir_path.join("hiper.exe");
-    #[cfg(not(windows))]
-    let hiper_path = hiper_dir_path.join("hiper");
+    let tap_path = hiper_dir_path.join(TAP_INSTALLER_FILE_NAME);
+    let wintun_path = hiper_dir_path.join(WINTUN_FILE_NAME);
+    let wintun_disabled_path = path_with_suffix(&wintun_path, ".disabled");
+    let installed_hiper_path = hiper_dir_path.join(HIPER_FILE_NAME);
+    let hiper_path = options.resolve_binary_path(&installed_hiper_path);
+    let hiper_backup_path = path_with_suffix(&installed_hiper_path, ".bak");
 
     std::fs::create_dir_all(&hiper_dir_path).context("无法创建 HiPer 安装目录")?;
+    std::fs::create_dir_all(&working_dir).context("无法创建 HiPer 工作目录")?;
+
+    // 先停止并回收本实例之前启动的 HiPer，避免新旧两个进程同时占用网卡和 hiper.pid，
+    // 同时也不会因为程序和驱动正在被使用而无法更新
+    if let Some(pid) = instance.pid() {
+        instance.stop_process();
+        process::remove_pid_file(&pid_file_path, pid);
+    }
 
     if !use_tun && wintun_path.exists() {
         std::fs::rename(&wintun_path, &wintun_disabled_path).context("无法禁用 WinTUN")?;
@@ -178,143 +323,78 @@ pub fn run_hiper(
     if use_tun {
         #[cfg(windows)]
         if !wintun_path.exists() {
-            let _ = ctx.submit_command(SET_START_TEXT, "正在下载安装 WinTUN", Target::Auto);
-            let res = tinyget
-                ::get(
-                    &format!(
-                        "https://gitcode.net/qq_43650370/hiper/-/raw/master/{}/wintun.dll",
-                        crate::utils::get_system_arch()
-                    )
+            if options.offline_mode {
+                anyhow::bail!("离线模式下无法下载 WinTUN，请先导入离线安装包");
+            }
+            enter_stage(&ctx, &instance, LaunchStage::InstallingWintun)?;
+            let arch = crate::utils::get_system_arch().to_string();
+            let part_path = driver_downloader
+                .download(
+                    &ctx,
+                    Some(&arch),
+                    WINTUN_FILE_NAME,
+                    &wintun_path,
+                    &mut progress_reporter(&ctx, &instance, LaunchStage::InstallingWintun)
                 )
-                .send()
                 .context("无法下载 WinTUN")?;
-            write_file_safe(&wintun_path, res.as_bytes()).context("无法安装 WinTUN")?;
+            install_file(&part_path, &wintun_path, None).context("无法安装 WinTUN")?;
         }
     } else {
         #[cfg(windows)]
         if !check_tap_installed() {
             if !tap_path.exists() {
-                let _ = ctx.submit_command(SET_START_TEXT, "正在下载 WinTAP", Target::Auto);
-                let res = tinyget
-                    ::get(
-                        "https://gitcode.net/qq_43650370/hiper/-/raw/master/tap-windows-9.21.2.exe"
+                if options.offline_mode {
+                    anyhow::bail!("离线模式下无法下载 WinTAP 安装程序，请先导入离线安装包");
+                }
+                enter_stage(&ctx, &instance, LaunchStage::DownloadingWintap)?;
+                let part_path = driver_downloader
+                    .download(
+                        &ctx,
+                        None,
+                        TAP_INSTALLER_DOWNLOAD_NAME,
+                        &tap_path,
+                        &mut progress_reporter(&ctx, &instance, LaunchStage::DownloadingWintap)
                     )
-                    .send()
                     .context("无法下载 WinTAP 安装程序")?;
-                write_file_safe(&tap_path, res.as_bytes()).context("无法写入 WinTAP 安装程序！")?;
+                install_file(&part_path, &tap_path, None).context("无法写入 WinTAP 安装程序！")?;
             }
-            let _ = ctx.submit_command(SET_START_TEXT, "正在安装 WinTAP", Target::Auto);
+            enter_stage(&ctx, &instance, LaunchStage::InstallingWintap)?;
 
             let c = Command::new(tap_path).arg("/S").status().context("无法运行 WinTAP 安装程序")?;
             c.code().context("无法安装 WinTAP")?;
         }
     }
 
-    let _update_available = false;
-
-    if !HAS_UPDATED.load(std::sync::atomic::Ordering::SeqCst) {
-        let arch = crate::utils::get_system_arch().to_string();
-        #[cfg(windows)]
-        let download_url =
-            format!("https://gitcode.net/qq_43650370/hiper/-/raw/master/{}/hiper.exe", arch);
-        #[cfg(not(windows))]
-        let download_url =
-            format!("https://gitcode.net/qq_43650370/hiper/-/raw/master/{}/hiper", arch);
-
-        if hiper_path.exists() {
-            let _ = ctx.submit_command(SET_START_TEXT, "正在检查 HiPer 更新", Target::Auto);
-
-            // 计算现有的 SHA1
-            let mut s = sha1_smol::Sha1::default();
-            s.update(&std::fs::read(&hiper_path).context("无法读取 HiPer 程序以计算摘要")?);
-            let current_hash = s.hexdigest();
-
-            let res = tinyget
-                ::get("https://gitcode.net/qq_43650370/hiper/-/raw/master/packages.sha1")
-                .send()
-                .context("无法获取 HiPer 下载索引")?
-                .as_str()
-                .context("无法解析 HiPer 下载索引")?
-                .to_owned();
-
-            for line in res.split('\n') {
-                if let Some((hash, path)) = line.split_once("  ") {
-                    #[cfg(windows)]
-                    let found = path.starts_with(&arch) && path.ends_with("hiper.exe");
-                    #[cfg(not(windows))]
-                    let found = path.starts_with(&arch) && path.ends_with("hiper");
-                    if found {
-                        println!("Comparing {} {} {} {}", arch, path, hash, current_hash);
-                        if hash != current_hash {
-                            let _ = ctx.submit_command(
-                                SET_START_TEXT,
-                                "正在更新 HiPer",
-                                Target::Auto
-                            );
-
-                            let res = tinyget
-                                ::get(download_url.as_str())
-                                .send()
-                                .context("无法下载 HiPer 程序")?;
-                            println!("HPR downloaded, size {}", res.as_bytes().len());
-
-                            write_file_safe(&hiper_path, res.as_bytes()).context(
-                                "无法更新 HiPer 程序"
-                            )?;
-                        }
-                        break;
-                    }
-                }
-            }
-        } else {
-            let _ = ctx.submit_command(SET_START_TEXT, "正在安装 HiPer", Target::Auto);
-
-            let res = tinyget::get(download_url.as_str()).send().context("无法下载 HiPer 程序")?;
-            println!("HPR downloaded, size {}", res.as_bytes().len());
-
-            write_file_safe(&hiper_path, res.as_bytes()).context("无法安装 HiPer 程序")?;
-
-            #[cfg(unix)]
-            {
-                std::process::Command
-                    ::new("chmod")
-                    .arg("+x")
-                    .arg(hiper_path.to_string_lossy().to_string())
-                    .status()
-                    .context("无法对 HiPer 程序增加可执行权限！")?;
-            }
+    // 本次启动前更新到的 HiPer 版本摘要，入网失败时用于回滚
+    // 使用自定义的 HiPer 程序时不做任何下载和更新
+    let updated_hash = if options.binary_path.is_some() {
+        if !hiper_path.is_file() {
+            anyhow::bail!("自定义的 HiPer 程序不存在：{}", hiper_path.to_string_lossy());
         }
-    }
-
-    let _ = ctx.submit_command(SET_START_TEXT, "正在启动 HiPer", Target::Auto);
-
-    let mut child = Command::new(hiper_path);
-
-    if has_token {
-        child.arg("-g");
-        child.arg(token);
-    }
-
-    if !use_tun {
-        child.arg("-t");
-    }
+        None
+    } else {
+        install_or_update_hiper(&ctx, &instance, &options, &hiper_path, &hiper_backup_path)?
+    };
+    hiper_version::report_installed_version(&ctx, &hiper_path);
 
-    if !use_igmp {
-        child.arg("-m");
-    }
+    enter_stage(&ctx, &instance, LaunchStage::Joining)?;
 
-    if !use_tcp {
-        child.arg("--tcp");
-    }
+    let mut child = options.build_command(&hiper_path);
+    child.current_dir(&working_dir);
 
-    if fast_mode {
-        child.arg("--fast");
+    // 保留上一次运行的日志，崩溃重启后仍然可以查看
+    let log_file_path = instance.log_file_path()?;
+    if let Err(err) = log_history::rotate(&log_file_path, options.log_history) {
+        crate::log_buffer::warn(format!("无法保存历史日志：{:?}", err));
     }
 
-    let (sender, reciver) = oneshot::channel::<String>();
+    let (sender, reciver) = oneshot::channel::<Result<String, HiperFailure>>();
 
     let ctx_c = ctx.to_owned();
+    let instance_c = instance.to_owned();
+    let binary_path = hiper_path.to_owned();
     std::thread::spawn(move || -> DynResult {
+        let instance = instance_c;
         #[cfg(windows)]
         use std::os::windows::process::CommandExt;
         #[cfg(windows)]
@@ -333,7 +413,7 @@ pub fn run_hiper(
             .spawn()
             .context("无法启动 HiPer")?;
 
-        plugin::dispatch_event("launch");
+        instance.dispatch_event("launch", &[]);
 
         #[cfg(all(windows, not(debug_assertions)))]
         if debug_mode {
@@ -354,7 +434,7 @@ pub fn run_hiper(
                             println!(
                                 "[WARN] 请不要直接停止控制台窗口！请点击主窗口的关闭按钮关闭 HiPer Bridge！"
                             );
-                            stop_hiper_directly();
+                            instance::stop_all();
                         }
                         _ => {}
                     }
@@ -367,225 +447,583 @@ pub fn run_hiper(
             }
         }
 
+        let (log_sender, log_reciver) = std::sync::mpsc::channel::<(LogSource, LogEvent)>();
         let stdout = child.stdout.take().context("无法获取 HiPer 输出流")?;
-        let mut stdout = BufReader::new(stdout);
-        let mut buf = String::with_capacity(256);
+        let stderr = child.stderr.take().context("无法获取 HiPer 错误输出流")?;
+        spawn_log_reader(stdout, LogSource::Stdout, log_sender.clone());
+        spawn_log_reader(stderr, LogSource::Stderr, log_sender);
 
-        stop_hiper_directly();
-        if let Ok(mut p) = SPAWNED_PROCESSES.lock() {
-            if p.is_none() {
-                *p = Some(Vec::with_capacity(16));
-            }
-            if let Some(p) = p.as_mut() {
-                p.push(child.id());
-            }
+        let process = Arc::new(HiperProcess::new(child, stop_timeout));
+        instance.register_process(process.clone());
+        // 在注册进程前就已经取消启动时，父线程无法停止这个进程
+        if instance.is_launch_cancelled() {
+            process.stop();
+        }
+        let mut pid_file = PidFile {
+            pid: process.id(),
+            ip: "".into(),
+            binary: binary_path,
+        };
+        if let Err(err) = process::write_pid_file(&pid_file_path, &pid_file) {
+            crate::log_buffer::warn(format!("无法写入 hiper.pid：{:?}", err));
         }
-        HIPER_PROCESS.store(child.id(), std::sync::atomic::Ordering::SeqCst);
 
         // Start Logging
         let mut logger_file = OpenOptions::new()
             .truncate(true)
             .write(true)
             .create(true)
-            .open(get_log_file_path()?)
+            .open(&log_file_path)
             .context("无法打开日志文件 (latest.log)!");
         let mut sender = Some(sender);
         let mut sent = false;
-        let mut no_more_logs = false;
+        // 入网后 HiPer 重连时可能会分配新的地址，需要持续跟踪
+        let mut current_ip = String::new();
+        let mut last_failure = None;
 
-        loop {
-            match stdout.read_line(&mut buf) {
-                Ok(len) => {
-                    no_more_logs |= len == 0;
-                    let line = buf[..len].trim();
-                    if len != 0 {
-                        println!("[HPR] {}", line);
-                        if let Ok(logger_file) = &mut logger_file {
-                            let _ = logger_file.write(line.as_bytes());
-                            let _ = logger_file.write(b"\n");
-                        }
+        // 两个输出流都关闭后才会结束循环
+        for (source, event) in log_reciver {
+            match source {
+                LogSource::Stdout => println!("[HPR] {}", event.raw),
+                LogSource::Stderr => println!("[HPR:ERR] {}", event.raw),
+            }
+            if let Ok(logger_file) = &mut logger_file {
+                let _ = writeln!(logger_file, "[{}] {}", source.tag(), event.raw);
+            }
+            crate::log_buffer::push_event(instance.name(), &event);
+            if let Some(address) = crate::log_parser::parse_virtual_address(&event) {
+                let ip = address.ip;
+                if sent {
+                    let new_ip = ip.to_string();
+                    if ip.is_unspecified() || new_ip == current_ip {
+                        continue;
                     }
-                    if let Some(ipv4) = crate::log_parser::try_get_ipv4(line) {
-                        if let Ok(ipv4) = ipv4.parse::<std::net::Ipv4Addr>() {
-                            if ipv4.is_unspecified() {
-                                if let Some(sender) = sender.take() {
-                                    sender
-                                        .send("".into())
-                                        .map_err(|x| {
-                                            anyhow::anyhow!(
-                                                "无法发送 IP 地址到父线程：{}",
-                                                x.as_inner()
-                                            )
-                                        })?;
-                                }
-                            } else if let Some(sender) = sender.take() {
-                                sender
-                                    .send(ipv4.to_string())
-                                    .map_err(|x| {
-                                        anyhow::anyhow!(
-                                            "无法发送 IP 地址到父线程：{}",
-                                            x.as_inner()
-                                        )
-                                    })?;
-                                plugin::dispatch_event("joined");
-                                sent = true;
-                            }
-                        }
+                    crate::log_buffer::info(format!(
+                        "{} 的 IP 地址已变更：{} -> {}",
+                        instance.name(),
+                        current_ip,
+                        new_ip
+                    ));
+                    if let Ok(logger_file) = &mut logger_file {
+                        let _ = writeln!(
+                            logger_file,
+                            "[bridge] IP 地址已变更：{} -> {}",
+                            current_ip,
+                            new_ip
+                        );
                     }
-                    // else if
-                    //     let Some((level, _msg, error)) = crate::log_parser::try_get_log_line(line)
-                    // {
-                    //     if &level == "error" {
-                    //         match error.as_str() {
-                    //             "Hiper certificate for this point is expired" => {
-                    //                 let _ = ctx_c.submit_command(
-                    //                     SET_WARNING,
-                    //                     "警告：凭证已过期！请使用新的凭证密钥重试！".to_string(),
-                    //                     Target::Auto
-                    //                 );
-                    //                 sent = false;
-                    //             }
-                    //             "Failed to open udp listener" => {
-                    //                 let _ = ctx_c.submit_command(
-                    //                     SET_WARNING,
-                    //                     "错误：HiPer无法监听服务端口，请确认端口占用情况".to_string(),
-                    //                     Target::Auto
-                    //                 );
-                    //                 sent = false;
-                    //             }
-                    //             "Failed to get a tun/tap device" => {
-                    //                 let _ = ctx_c.submit_command(
-                    //                     SET_WARNING,
-                    //                     "错误：无法获取 TUN/TAP 设备！这应该是你多开了 HiPer 导致设备被占用了".to_string(),
-                    //                     Target::Auto
-                    //                 );
-                    //                 sent = false;
-                    //             }
-                    //             _ => {
-                    //                 // let _ = ctx_c.submit_command(
-                    //                 //     SET_WARNING,
-                    //                 //     "错误：HiPer 启动失败！请检查 latest.log 日志文件确认问题！".to_string(),
-                    //                 //     Target::Auto,
-                    //                 // );
-                    //                 // sent = false;
-                    //             }
-                    //         }
-                    //         std::thread::sleep(std::time::Duration::from_secs(5));
-                    //         let _ = ctx_c.submit_command(SET_WARNING, "".to_string(), Target::Auto);
-                    //     }
-                    // }
-                    if no_more_logs {
-                        if let Ok(Some(_)) = child.try_wait() {
-                            if let Some(sender) = sender.take() {
-                                sender
-                                    .send("".into())
-                                    .map_err(|x| {
-                                        anyhow::anyhow!("无法发送消息到父线程：{}", x.as_inner())
-                                    })?;
-                            }
-                            break;
-                        }
+                    let _ = ctx_c.submit_command(SET_IP, (id, new_ip.to_owned()), Target::Auto);
+                    pid_file.ip = new_ip.to_owned();
+                    let _ = process::write_pid_file(&pid_file_path, &pid_file);
+                    let old_ip = std::mem::replace(&mut current_ip, new_ip);
+                    instance.dispatch_event(
+                        "ip-changed",
+                        &[
+                            ("HIPER_OLD_IP", old_ip),
+                            ("HIPER_IP", current_ip.to_owned()),
+                        ]
+                    );
+                } else if ip.is_unspecified() {
+                    if let Some(sender) = sender.take() {
+                        let _ = sender.send(
+                            Err(last_failure.take().unwrap_or(HiperFailure::NoAddress))
+                        );
                     }
-                    buf.clear();
-                }
-                Err(err) => {
-                    println!("警告：解析日志发生错误：{:?}", err);
+                } else if let Some(sender) = sender.take() {
+                    // 发送失败说明启动已经超时或被取消，进程会由父线程停止
+                    if sender.send(Ok(ip.to_string())).is_err() {
+                        continue;
+                    }
+                    crate::log_buffer::info(format!("{} 已入网，虚拟地址为 {}", instance.name(), address));
+                    instance.dispatch_event("joined", &[]);
+                    instance.supervisor().mark_started();
+                    current_ip = ip.to_string();
+                    pid_file.ip = current_ip.to_owned();
+                    let _ = process::write_pid_file(&pid_file_path, &pid_file);
+                    sent = true;
                 }
+            } else if let Some(failure) = HiperFailure::from_log_event(&event) {
+                crate::log_buffer::warn(format!("检测到 HiPer 错误：{}", failure));
+                let _ = ctx_c.submit_command(SET_WARNING, (id, failure.message()), Target::Auto);
+                last_failure = Some(failure);
             }
         }
+
+        // 输出流关闭后进程可能还没有完全退出，需要等待并回收
+        process.wait_timeout(None);
+        let status = process.exit_status();
+        instance.unregister_process(&process);
+        process::remove_pid_file(&pid_file_path, process.id());
+
+        if let Some(sender) = sender.take() {
+            let failure = last_failure
+                .take()
+                .unwrap_or_else(|| HiperFailure::from_exit_status(status.as_ref()));
+            let _ = sender.send(Err(failure));
+        }
+
         #[cfg(all(windows, not(debug_assertions)))]
         if debug_mode {
             unsafe {
                 windows::Win32::System::Console::FreeConsole();
             }
         }
-        println!("[WARN] HiPer 已退出！");
-        plugin::dispatch_event("stopped");
-
-        if
-            sent &&
-            !child
-                .wait()
-                .map(|x| x.success())
-                .unwrap_or(false)
-        {
-            let _ = ctx_c.submit_command(
-                SET_WARNING,
-                "警告：HiPer 服务程序已退出！若是意外退出，守护程序将在 5 秒后自动重启服务程序！\n\n　　如需阻止自动重启，请点击关闭按钮！".to_string(),
-                Target::Auto
-            );
-            plugin::dispatch_event("crashed");
-            let _ = ctx_c.submit_command(REQUEST_RESTART, (), Target::Auto);
+        let exit_description = status
+            .as_ref()
+            .map(describe_exit_status)
+            .unwrap_or_else(|| "未知的退出方式".into());
+        crate::log_buffer::warn(format!("{} 的 HiPer 已退出！（{}）", instance.name(), exit_description));
+        if let Ok(logger_file) = &mut logger_file {
+            let _ = writeln!(logger_file, "[bridge] HiPer 已退出：{}", exit_description);
+        }
+        let mut exit_env = status.as_ref().map(exit_status_env).unwrap_or_default();
+        instance.dispatch_event("stopped", &exit_env);
+
+        if sent && !process.is_stop_requested() {
+            let generation = leave_running(&ctx_c, &instance);
+            if !status.map(|x| x.success()).unwrap_or(false) {
+                let warning = match &last_failure {
+                    Some(failure) => failure.message(),
+                    None => format!("警告：HiPer 服务程序已意外退出（{}）！", exit_description),
+                };
+                let _ = ctx_c.submit_command(SET_WARNING, (id, warning), Target::Auto);
+                if let Some(failure) = &last_failure {
+                    exit_env.push(failure.env());
+                }
+                instance.dispatch_event("crashed", &exit_env);
+                let _ = ctx_c.submit_command(REQUEST_RESTART, (id, generation), Target::Auto);
+            }
         }
         Ok(())
     });
 
-    let ip = reciver.recv().context("未能从 HiPer 输出中获取 IP 地址")?;
+    match wait_for_join(&instance, &reciver, join_timeout)? {
+        None => {
+            instance.stop_process();
+            return Err(LaunchCancelled.into());
+        }
+        Some(Err(failure)) => {
+            instance.set_stage(&ctx, ConnectionState::new(LaunchStage::Idle));
+            let _ = ctx.submit_command(SET_IP, (id, "".to_string()), Target::Auto);
+            instance.stop_process();
+            crate::log_buffer::warn(format!("{} 的 HiPer 入网失败：{}", instance.name(), failure.id()));
+            let mut warning = failure.message();
+            if let Some(hash) = updated_hash.filter(|_| failure.may_be_caused_by_binary()) {
+                match rollback_hiper(&hiper_path, &hiper_backup_path, hash, is_hiper_in_use(&instance)) {
+                    Ok(()) => {
+                        warning = format!(
+                            "错误：更新后的 HiPer 未能入网（{}），已自动回滚到之前的版本，请重新启动！",
+                            failure.id()
+                        );
+                    }
+                    Err(err) => {
+                        crate::log_buffer::warn(format!("无法回滚 HiPer：{:?}", err));
+                    }
+                }
+            }
+            let _ = ctx.submit_command(SET_WARNING, (id, warning), Target::Auto);
+            instance.dispatch_event("failed", &[failure.env()]);
+        }
+        Some(Ok(ip)) => {
+            if !has_token {
+                let _ = ctx.submit_command(
+                    SET_WARNING,
+                    (
+                        id,
+                        "警告：没有提供凭证，HiPer 将使用临时网络连接并将会在半小时后断连！".to_string(),
+                    ),
+                    Target::Auto
+                );
+            }
+            let _ = ctx.submit_command(SET_IP, (id, ip), Target::Auto);
+            instance.set_stage(&ctx, ConnectionState::new(LaunchStage::Running));
+        }
+    }
+
+    Ok(())
+}
+
+/// 按更新设置安装或更新 HiPer，返回本次更新到的版本摘要，没有更新时返回 `None`
+///
+/// 已经安装了 HiPer 时，离线模式或无法连接到下载镜像都不会阻止启动，只会给出警告
+fn install_or_update_hiper(
+    ctx: &ExtEventSink,
+    instance: &HiperInstance,
+    options: &HiperLaunchOptions,
+    hiper_path: &Path,
+    hiper_backup_path: &Path
+) -> DynResult<Option<String>> {
+    let id = instance.id();
+    let update = &options.update;
+    let arch = crate::utils::get_system_arch().to_string();
+
+    // 计算现有的 SHA1
+    let current_hash = if hiper_path.exists() {
+        Some(sha1_hex(&std::fs::read(hiper_path).context("无法读取 HiPer 程序以计算摘要")?))
+    } else {
+        None
+    };
+    let now = chrono::Utc::now().timestamp();
 
-    if ip.is_empty() {
-        let _ = ctx.submit_command(SET_START_TEXT, "启动", Target::Auto);
-        stop_hiper_directly();
-        if !has_token {
+    if options.offline_mode {
+        if current_hash.is_none() {
+            anyhow::bail!("离线模式下没有找到已安装的 HiPer，请先导入离线安装包");
+        }
+        crate::log_buffer::info("离线模式，跳过 HiPer 更新检查");
+        return Ok(None);
+    }
+
+    if !update.needs_check(current_hash.as_deref(), crate::update::checked_source().as_deref(), now) {
+        return Ok(None);
+    }
+
+    enter_stage(
+        ctx,
+        instance,
+        if current_hash.is_some() {
+            LaunchStage::CheckingUpdate
+        } else {
+            LaunchStage::InstallingHiper
+        }
+    )?;
+
+    let mut hiper_downloader = VerifiedDownloader::new(
+        options.mirrors.to_owned(),
+        update.index_dir()
+    );
+    let index = match hiper_downloader.index(ctx) {
+        Ok(index) => index,
+        Err(err) if current_hash.is_some() => {
+            crate::log_buffer::warn(format!("无法获取 HiPer 下载索引，将使用已安装的 HiPer：{:?}", err));
             let _ = ctx.submit_command(
                 SET_WARNING,
-                "错误：HiPer 入网失败！请检查凭证密钥是否填写正确！".to_string(),
+                (id, "警告：无法连接到下载镜像，已跳过更新检查并使用已安装的 HiPer".to_string()),
                 Target::Auto
             );
+            return Ok(None);
         }
-    } else {
-        if !has_token {
-            let _ = ctx.submit_command(
-                SET_WARNING,
-                "警告：没有提供凭证，HiPer 将使用临时网络连接并将会在半小时后断连！".to_string(),
-                Target::Auto
+        Err(err) => {
+            return Err(err);
+        }
+    };
+    let latest_hash = index
+        .find(&arch, HIPER_FILE_NAME)
+        .and_then(|path| index.sha1_of(path))
+        .map(str::to_owned)
+        .context("下载索引中没有适用于当前系统的 HiPer")?;
+    if latest_hash.len() != 40 || !latest_hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("下载索引中 HiPer 的 SHA-1 摘要格式不正确：{}", latest_hash);
+    }
+    update.mark_checked();
+    let _ = ctx.submit_command(SET_LAST_UPDATE_CHECK, now, Target::Auto);
+    println!("Comparing {} {} {:?}", arch, latest_hash, current_hash);
+
+    if let Some(pinned) = update.pinned_hash() {
+        if pinned != latest_hash {
+            anyhow::bail!(
+                "镜像中的 HiPer 和固定的版本不一致（固定为 {}，镜像中为 {}），请更换镜像或手动安装固定的版本",
+                pinned,
+                latest_hash
             );
         }
-        let _ = ctx.submit_command(SET_IP, ip, Target::Auto);
-        let _ = ctx.submit_command(SET_START_TEXT, "返回", Target::Auto);
     }
 
+    // 在安装前展示镜像中的新版本
+    if current_hash.as_deref() == Some(latest_hash.as_str()) {
+        hiper_version::report_available(ctx, None);
+    } else if current_hash.is_some() {
+        let mut notes = ReleaseNotes::fetch(&options.mirrors, &update.index_dir());
+        if notes.version.is_empty() {
+            notes.version = latest_hash.get(..8).unwrap_or(&latest_hash).to_owned();
+        }
+        crate::log_buffer::info(format!("镜像中的 HiPer 版本为 {}", notes.version));
+        hiper_version::report_available(ctx, Some(notes));
+    }
+
+    let current_hash = match current_hash {
+        Some(current_hash) => current_hash,
+        None => {
+            let part_path = hiper_downloader
+                .download(
+                    ctx,
+                    Some(&arch),
+                    HIPER_FILE_NAME,
+                    hiper_path,
+                    &mut progress_reporter(ctx, instance, LaunchStage::InstallingHiper)
+                )
+                .context("无法下载 HiPer 程序")?;
+            println!("HPR downloaded to {}", part_path.to_string_lossy());
+
+            install_file(&part_path, hiper_path, None).context("无法安装 HiPer 程序")?;
+            return Ok(None);
+        }
+    };
+
+    let should_install = update.should_install(Some(&current_hash), &latest_hash) && !is_rolled_back(&latest_hash);
+    if should_install && is_hiper_in_use(instance) {
+        crate::log_buffer::info("其它网络正在使用 HiPer，本次跳过 HiPer 更新");
+    } else if should_install {
+        enter_stage(ctx, instance, LaunchStage::UpdatingHiper)?;
+
+        // 校验或替换失败时保留原有的 HiPer 程序继续启动
+        let result = hiper_downloader
+            .download(
+                ctx,
+                Some(&arch),
+                HIPER_FILE_NAME,
+                hiper_path,
+                &mut progress_reporter(ctx, instance, LaunchStage::UpdatingHiper)
+            )
+            .and_then(|part_path| {
+                println!("HPR downloaded to {}", part_path.to_string_lossy());
+                install_file(&part_path, hiper_path, Some(hiper_backup_path)).context("无法更新 HiPer 程序")
+            });
+        match result {
+            Ok(()) => {
+                hiper_version::report_available(ctx, None);
+                return Ok(Some(latest_hash));
+            }
+            Err(err) if err.is::<LaunchCancelled>() => {
+                return Err(err);
+            }
+            Err(err) => {
+                crate::log_buffer::warn(format!("HiPer 更新失败，将继续使用现有的 HiPer：{:?}", err));
+                let _ = ctx.submit_command(
+                    SET_WARNING,
+                    (id, format!("警告：HiPer 更新失败，已保留现有版本：{}", err)),
+                    Target::Auto
+                );
+            }
+        }
+    } else if current_hash != latest_hash && update.policy == UpdatePolicy::CheckOnly {
+        crate::log_buffer::info(format!("发现 HiPer 新版本 {}，当前设置为仅检查更新", latest_hash));
+        let _ = ctx.submit_command(
+            SET_WARNING,
+            (id, "发现 HiPer 新版本，可以在设置中查看更新日志，将更新方式改为自动更新后安装".to_string()),
+            Target::Auto
+        );
+    }
+    Ok(None)
+}
+
+fn is_rolled_back(hash: &str) -> bool {
+    ROLLED_BACK_HASHES.lock()
+        .map(|x| x.iter().any(|x| x == hash))
+        .unwrap_or(false)
+}
+
+/// 是否有其它网络的 HiPer 正在运行，此时 HiPer 程序正在被使用，Windows 下无法被替换
+fn is_hiper_in_use(instance: &HiperInstance) -> bool {
+    instance::all()
+        .iter()
+        .any(|x| x.id() != instance.id() && x.is_running())
+}
+
+/// 使用更新前备份的 HiPer 替换掉无法入网的新版本，并在本次运行中不再更新到该版本
+///
+/// `in_use` 为其它网络是否正在使用 HiPer，见 [`is_hiper_in_use`]，此时不会替换程序
+fn rollback_hiper(hiper_path: &Path, backup_path: &Path, hash: String, in_use: bool) -> DynResult {
+    if in_use {
+        anyhow::bail!("其它网络正在使用 HiPer，无法回滚");
+    }
+    if !backup_path.is_file() {
+        anyhow::bail!("没有找到更新前的 HiPer 备份");
+    }
+    std::fs::rename(backup_path, hiper_path).context("无法恢复更新前的 HiPer 程序")?;
+    crate::log_buffer::warn(format!("已回滚 HiPer，版本 {} 将不会再被自动更新", hash));
+    if let Ok(mut hashes) = ROLLED_BACK_HASHES.lock() {
+        hashes.push(hash);
+    }
     Ok(())
 }
 
-fn stop_process(pid: u32) {
-    #[cfg(windows)]
-    unsafe {
-        if let Ok(handle) = OpenProcess(PROCESS_SYNCHRONIZE | PROCESS_TERMINATE, false, pid) {
-            TerminateProcess(handle, 0);
-            let _r = WaitForSingleObject(handle, 0);
+/// 等待 HiPer 的入网结果，用户取消启动时返回 `None`
+///
+/// 超过入网超时后会返回 [`HiperFailure::JoinTimeout`]，由调用方停止 HiPer
+fn wait_for_join(
+    instance: &HiperInstance,
+    reciver: &oneshot::Receiver<Result<String, HiperFailure>>,
+    timeout: Option<Duration>
+) -> DynResult<Option<Result<String, HiperFailure>>> {
+    let start = Instant::now();
+    loop {
+        if instance.is_launch_cancelled() {
+            return Ok(None);
+        }
+        if let Some(timeout) = timeout {
+            if start.elapsed() >= timeout {
+                return Ok(Some(Err(HiperFailure::JoinTimeout(timeout.as_secs()))));
+            }
+        }
+        match reciver.recv_timeout(Duration::from_millis(100)) {
+            Ok(result) => {
+                return Ok(Some(result));
+            }
+            Err(oneshot::RecvTimeoutError::Timeout) => {}
+            Err(oneshot::RecvTimeoutError::Disconnected) => {
+                anyhow::bail!("未能从 HiPer 输出中获取 IP 地址");
+            }
         }
     }
-    #[cfg(unix)]
-    unsafe {
-        nix::libc::kill(pid as i32, nix::libc::SIGTERM);
+}
+
+/// 生成下载进度的回调，进度会作为当前阶段的进度信息显示，用户取消启动时停止下载
+fn progress_reporter<'a>(
+    ctx: &'a ExtEventSink,
+    instance: &'a HiperInstance,
+    stage: LaunchStage
+) -> impl FnMut(&DownloadProgress) -> DynResult + 'a {
+    move |progress| {
+        if instance.is_launch_cancelled() {
+            return Err(LaunchCancelled.into());
+        }
+        instance.set_stage(ctx, ConnectionState::with_detail(stage, progress.to_string()));
+        Ok(())
     }
 }
 
-pub fn is_running() -> bool {
-    HIPER_PROCESS.load(std::sync::atomic::Ordering::SeqCst) != 0
+/// 进入启动过程中的下一个阶段，用户已经取消启动时返回 [`LaunchCancelled`]
+fn enter_stage(ctx: &ExtEventSink, instance: &HiperInstance, stage: LaunchStage) -> DynResult {
+    if instance.is_launch_cancelled() {
+        return Err(LaunchCancelled.into());
+    }
+    instance.set_stage(ctx, ConnectionState::new(stage));
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogSource {
+    Stdout,
+    Stderr,
 }
 
-pub fn stop_hiper_directly() {
-    let pid = HIPER_PROCESS.swap(0, std::sync::atomic::Ordering::SeqCst);
-    if pid != 0 {
-        stop_process(pid);
+impl LogSource {
+    fn tag(self) -> &'static str {
+        match self {
+            LogSource::Stdout => "stdout",
+            LogSource::Stderr => "stderr",
+        }
     }
-    if let Ok(mut p) = SPAWNED_PROCESSES.lock() {
-        if let Some(p) = p.as_mut() {
-            for pid in p.drain(..) {
-                stop_process(pid);
+}
+
+/// 在独立线程中逐行读取并解析 HiPer 的输出流，避免管道缓冲区被写满导致 HiPer 阻塞
+fn spawn_log_reader(
+    stream: impl Read + Send + 'static,
+    source: LogSource,
+    sender: Sender<(LogSource, LogEvent)>
+) {
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut buf = Vec::with_capacity(256);
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) => {
+                    break;
+                }
+                Ok(_) => {
+                    let line = String::from_utf8_lossy(&buf).trim().to_owned();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    if sender.send((source, LogEvent::parse(&line))).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    println!("警告：读取 HiPer {} 输出流发生错误：{:?}", source.tag(), err);
+                    break;
+                }
             }
         }
-    }
+    });
 }
 
-pub fn stop_hiper(ctx: ExtEventSink) {
-    let _ = ctx.submit_command(SET_START_TEXT, "正在关闭 HiPer", Target::Auto);
-    let _ = ctx.submit_command(SET_WARNING, "".to_string(), Target::Auto);
-    let _ = ctx.submit_command(SET_IP, "".to_string(), Target::Auto);
+pub fn stop_hiper(ctx: ExtEventSink, id: InstanceId) {
+    let instance = instance::get(id);
+    instance.set_stage(&ctx, ConnectionState::new(LaunchStage::Stopping));
+    let _ = ctx.submit_command(SET_WARNING, (id, "".to_string()), Target::Auto);
+    let _ = ctx.submit_command(SET_IP, (id, "".to_string()), Target::Auto);
     let _ = ctx.submit_command(SET_VALID, "".to_string(), Target::Auto);
 
-    stop_hiper_directly();
+    instance.supervisor().cancel_pending();
+    let status = instance.stop_process();
+
+    instance.set_stage(&ctx, ConnectionState::new(LaunchStage::Idle));
+    if let Some(status) = status {
+        let _ = ctx.submit_command(
+            SET_WARNING,
+            (id, format!("HiPer 已停止运行（{}）", describe_exit_status(&status))),
+            Target::Auto
+        );
+    }
+}
+
+/// 在后台线程中停止指定实例的 HiPer，停止期间会禁用该实例的操作
+pub fn stop_hiper_in_thread(ctx: ExtEventSink, id: InstanceId) {
+    std::thread::spawn(move || {
+        let _ = ctx.submit_command(SET_DISABLED, (id, true), Target::Auto);
+        stop_hiper(ctx.to_owned(), id);
+        let _ = ctx.submit_command(SET_DISABLED, (id, false), Target::Auto);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let _ = ctx.submit_command(SET_START_TEXT, "启动", Target::Auto);
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hiper-bridge-rollback-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn rollback_restores_backup_and_blocks_version() {
+        let dir = temp_dir("restore");
+        let hiper_path = dir.join("hiper");
+        let backup_path = dir.join("hiper.bak");
+        std::fs::write(&hiper_path, b"new").unwrap();
+        std::fs::write(&backup_path, b"old").unwrap();
+        let hash = sha1_hex(b"rollback-restore");
+
+        rollback_hiper(&hiper_path, &backup_path, hash.to_owned(), false).unwrap();
+
+        assert_eq!(std::fs::read(&hiper_path).unwrap(), b"old");
+        assert!(!backup_path.exists());
+        assert!(is_rolled_back(&hash));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn rollback_keeps_binary_in_use_by_other_network() {
+        let dir = temp_dir("in-use");
+        let hiper_path = dir.join("hiper");
+        let backup_path = dir.join("hiper.bak");
+        std::fs::write(&hiper_path, b"new").unwrap();
+        std::fs::write(&backup_path, b"old").unwrap();
+        let hash = sha1_hex(b"rollback-in-use");
+
+        assert!(rollback_hiper(&hiper_path, &backup_path, hash.to_owned(), true).is_err());
+
+        assert_eq!(std::fs::read(&hiper_path).unwrap(), b"new");
+        assert_eq!(std::fs::read(&backup_path).unwrap(), b"old");
+        assert!(!is_rolled_back(&hash));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn rollback_without_backup_fails() {
+        let dir = temp_dir("missing");
+        let hiper_path = dir.join("hiper");
+        std::fs::write(&hiper_path, b"new").unwrap();
+        let hash = sha1_hex(b"rollback-missing");
+
+        assert!(rollback_hiper(&hiper_path, &dir.join("hiper.bak"), hash.to_owned(), false).is_err());
+
+        assert_eq!(std::fs::read(&hiper_path).unwrap(), b"new");
+        assert!(!is_rolled_back(&hash));
+        let _ = std::fs::remove_dir_all(dir);
+    }
 }
@@ -0,0 +1,130 @@
+//! 已安装的 HiPer 版本和镜像中新版本的说明
+//!
+//! HiPer 的版本号通过 `hiper -version` 获取，结果会和程序的 SHA-1 摘要一起缓存在程序旁的
+//! `.version` 文件中，程序不变时不会再次运行。下载镜像的更新目录中可以额外提供 `version`
+//! 和 `changelog.txt`，用于在更新前展示新版本的版本号和更新日志
+
+use std::{
+    io::Read,
+    path::Path,
+    process::{ Command, Stdio },
+    time::{ Duration, Instant },
+};
+
+use anyhow::Context;
+use druid::{ ExtEventSink, Target };
+
+use crate::{
+    integrity::sha1_hex,
+    mirror::MirrorList,
+    ui::{ SET_AVAILABLE_HIPER, SET_HIPER_VERSION },
+    utils::{ path_with_suffix, write_file_safe },
+    DynResult,
+};
+
+/// 等待 `hiper -version` 退出的最长时间，超时后会结束进程
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 镜像中新版本的说明
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReleaseNotes {
+    /// 新版本的版本号，镜像没有提供时为空
+    pub version: String,
+    /// 新版本的更新日志，镜像没有提供时为空
+    pub changelog: String,
+}
+
+impl ReleaseNotes {
+    /// 从镜像的 `dir` 目录获取新版本的说明，两个文件都是可选的
+    pub fn fetch(mirrors: &MirrorList, dir: &str) -> Self {
+        let read = |name: &str| {
+            mirrors
+                .fetch_optional(|mirror| mirror.in_dir(dir).file_url(name))
+                .ok()
+                .and_then(|res| res.as_str().ok().map(|x| x.trim().to_owned()))
+                .unwrap_or_default()
+        };
+        Self {
+            version: read("version"),
+            changelog: read("changelog.txt"),
+        }
+    }
+}
+
+/// 从 `hiper -version` 的输出中找出版本号，即第一个以数字开头且包含 `.` 的词
+fn parse_version_output(output: &str) -> Option<String> {
+    output
+        .split_whitespace()
+        .map(|x| x.trim_matches(|c: char| !c.is_ascii_alphanumeric()))
+        .map(|x| x.strip_prefix('v').unwrap_or(x))
+        .find(|x| x.starts_with(|c: char| c.is_ascii_digit()) && x.contains('.'))
+        .map(str::to_owned)
+}
+
+/// 运行 HiPer 查询版本号
+fn query_version(hiper_path: &Path) -> DynResult<String> {
+    let mut command = Command::new(hiper_path);
+    command.arg("-version").stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        command.creation_flags(0x08000000);
+    }
+    let mut child = command.spawn().context("无法运行 HiPer 以查询版本")?;
+    let start = Instant::now();
+    while child.try_wait()?.is_none() {
+        if start.elapsed() >= QUERY_TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            anyhow::bail!("查询 HiPer 版本超时");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let mut output = String::new();
+    if let Some(mut stdout) = child.stdout.take() {
+        let _ = stdout.read_to_string(&mut output);
+    }
+    if let Some(mut stderr) = child.stderr.take() {
+        let _ = stderr.read_to_string(&mut output);
+    }
+    parse_version_output(&output).with_context(|| format!("无法从 HiPer 的输出中找到版本号：{}", output.trim()))
+}
+
+/// 获取 HiPer 程序的版本号，程序的摘要和缓存一致时直接使用缓存
+pub fn installed_version(hiper_path: &Path) -> DynResult<String> {
+    let hash = sha1_hex(&std::fs::read(hiper_path).context("无法读取 HiPer 程序以计算摘要")?);
+    let cache_path = path_with_suffix(hiper_path, ".version");
+    if let Ok(cache) = std::fs::read_to_string(&cache_path) {
+        if let Some((cached_hash, version)) = cache.trim().split_once(' ') {
+            if cached_hash == hash {
+                return Ok(version.to_owned());
+            }
+        }
+    }
+    let version = query_version(hiper_path)?;
+    if let Err(err) = write_file_safe(&cache_path, format!("{} {}\n", hash, version).as_bytes()) {
+        crate::log_buffer::warn(format!("无法缓存 HiPer 版本：{:?}", err));
+    }
+    Ok(version)
+}
+
+/// 获取 HiPer 的版本号并显示在设置页中，获取失败时显示为未知
+pub fn report_installed_version(ctx: &ExtEventSink, hiper_path: &Path) {
+    if !hiper_path.is_file() {
+        return;
+    }
+    let version = match installed_version(hiper_path) {
+        Ok(version) => version,
+        Err(err) => {
+            crate::log_buffer::warn(format!("无法获取 HiPer 版本：{:?}", err));
+            "".into()
+        }
+    };
+    let _ = ctx.submit_command(SET_HIPER_VERSION, version, Target::Auto);
+}
+
+/// 在设置页中显示镜像中的新版本，`notes` 为 `None` 时表示已是最新版本
+pub fn report_available(ctx: &ExtEventSink, notes: Option<ReleaseNotes>) {
+    let notes = notes.unwrap_or_default();
+    let _ = ctx.submit_command(SET_AVAILABLE_HIPER, (notes.version, notes.changelog), Target::Auto);
+}
@@ -0,0 +1,226 @@
+//! HiPer 实例的管理
+//!
+//! 每个实例对应一个使用独立凭证加入的网络，拥有自己的工作目录、日志文件、进程和崩溃守护，
+//! 多个实例可以同时运行。主网络（设置页和主页中的凭证）固定为 [`PRIMARY_INSTANCE`]，
+//! 其工作目录就是 HiPer 安装目录，以便兼容旧版本的 `latest.log` 和 `hiper.pid` 位置
+
+use std::{
+    path::PathBuf,
+    process::ExitStatus,
+    sync::{ atomic::{ AtomicBool, Ordering }, Arc, Mutex, MutexGuard },
+};
+
+use druid::{ ExtEventSink, Target };
+
+use crate::{
+    hiper::get_hiper_dir,
+    plugin,
+    process::HiperProcess,
+    stage::{ ConnectionState, LaunchStage },
+    supervisor::{ RestartPolicy, Supervisor },
+    ui::SET_STAGE,
+    DynResult,
+};
+
+pub type InstanceId = usize;
+
+/// 主网络的实例编号
+pub const PRIMARY_INSTANCE: InstanceId = 0;
+
+static INSTANCES: Mutex<Vec<Arc<HiperInstance>>> = Mutex::new(Vec::new());
+
+pub struct HiperInstance {
+    id: InstanceId,
+    process: Mutex<Option<Arc<HiperProcess>>>,
+    supervisor: Mutex<Supervisor>,
+    stage: Mutex<LaunchStage>,
+    /// 用户是否取消了正在进行的启动
+    launch_cancelled: AtomicBool,
+}
+
+impl HiperInstance {
+    fn new(id: InstanceId) -> Self {
+        Self {
+            id,
+            process: Mutex::new(None),
+            supervisor: Mutex::new(Supervisor::new(RestartPolicy::default())),
+            stage: Mutex::new(LaunchStage::Idle),
+            launch_cancelled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn id(&self) -> InstanceId {
+        self.id
+    }
+
+    pub fn is_primary(&self) -> bool {
+        self.id == PRIMARY_INSTANCE
+    }
+
+    /// 用于日志输出的实例名称
+    pub fn name(&self) -> String {
+        if self.is_primary() { "主网络".into() } else { format!("网络 {}", self.id) }
+    }
+
+    /// 实例的工作目录，HiPer 会在该目录下运行，日志和进程记录也保存在这里
+    pub fn working_dir(&self) -> DynResult<PathBuf> {
+        let hiper_dir = get_hiper_dir()?;
+        if self.is_primary() {
+            Ok(hiper_dir)
+        } else {
+            Ok(hiper_dir.join("instances").join(self.id.to_string()))
+        }
+    }
+
+    pub fn log_file_path(&self) -> DynResult<PathBuf> {
+        use path_absolutize::*;
+        Ok(
+            self
+                .working_dir()?
+                .join("latest.log")
+                .absolutize()
+                .map(|x| x.to_path_buf())?
+        )
+    }
+
+    pub fn pid_file_path(&self) -> DynResult<PathBuf> {
+        Ok(self.working_dir()?.join("hiper.pid"))
+    }
+
+    pub fn register_process(&self, process: Arc<HiperProcess>) {
+        if let Ok(mut p) = self.process.lock() {
+            *p = Some(process);
+        }
+    }
+
+    /// 进程退出后将其从实例中移除，如果已经被新的进程替换则不做处理
+    pub fn unregister_process(&self, process: &Arc<HiperProcess>) {
+        if let Ok(mut p) = self.process.lock() {
+            if p.as_ref().map(|x| Arc::ptr_eq(x, process)).unwrap_or(false) {
+                *p = None;
+            }
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.process
+            .lock()
+            .map(|x| x.is_some())
+            .unwrap_or(false)
+    }
+
+    /// 当前运行的 HiPer 进程编号
+    pub fn pid(&self) -> Option<u32> {
+        self.process.lock().ok()?.as_ref().map(|x| x.id())
+    }
+
+    /// 停止实例当前运行的 HiPer 并等待其退出，返回 HiPer 的退出状态
+    pub fn stop_process(&self) -> Option<ExitStatus> {
+        let process = self.process.lock().ok()?.take()?;
+        println!("Stopping hiper process {} of {}", process.id(), self.name());
+        process.stop()
+    }
+
+    pub fn stage(&self) -> LaunchStage {
+        *self.stage.lock().unwrap_or_else(|x| x.into_inner())
+    }
+
+    /// 切换实例的启动阶段，并通知界面、插件和日志
+    ///
+    /// 不允许的切换会被忽略，阶段不变时仅更新界面上的进度信息
+    pub fn set_stage(&self, ctx: &ExtEventSink, state: ConnectionState) {
+        let previous = {
+            let mut stage = self.stage.lock().unwrap_or_else(|x| x.into_inner());
+            let previous = *stage;
+            if !previous.can_transition_to(state.stage) {
+                crate::log_buffer::warn(format!(
+                    "{} 的启动阶段不能从 {} 切换到 {}",
+                    self.name(),
+                    previous.id(),
+                    state.stage.id()
+                ));
+                return;
+            }
+            *stage = state.stage;
+            previous
+        };
+        if previous != state.stage {
+            println!("[STAGE] {}：{} -> {}", self.name(), previous.id(), state.stage.id());
+            let mut env = state.env();
+            env.push(("HIPER_PREVIOUS_STAGE", previous.id().to_owned()));
+            self.dispatch_event("stage-changed", &env);
+        }
+        let _ = ctx.submit_command(SET_STAGE, (self.id, state), Target::Auto);
+    }
+
+    /// 开始一次新的启动，清除上一次启动的取消请求
+    pub fn begin_launch(&self) {
+        self.launch_cancelled.store(false, Ordering::SeqCst);
+    }
+
+    /// 请求取消正在进行的启动，启动线程会在下一次检查时停止 HiPer
+    pub fn cancel_launch(&self) {
+        self.launch_cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_launch_cancelled(&self) -> bool {
+        self.launch_cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn supervisor(&self) -> MutexGuard<'_, Supervisor> {
+        self.supervisor.lock().unwrap_or_else(|x| x.into_inner())
+    }
+
+    /// 触发插件事件，会额外传递实例编号 `HIPER_INSTANCE`
+    pub fn dispatch_event(&self, name: &str, env: &[(&str, String)]) {
+        let mut env = env.to_vec();
+        env.push(("HIPER_INSTANCE", self.id.to_string()));
+        plugin::dispatch_event_with_env(name, &env);
+    }
+}
+
+/// 获取指定编号的实例，不存在时会创建一个新的实例
+pub fn get(id: InstanceId) -> Arc<HiperInstance> {
+    let mut instances = INSTANCES.lock().unwrap_or_else(|x| x.into_inner());
+    if let Some(instance) = instances.iter().find(|x| x.id == id) {
+        return instance.to_owned();
+    }
+    let instance = Arc::new(HiperInstance::new(id));
+    instances.push(instance.to_owned());
+    instance
+}
+
+/// 移除不再使用的实例并作废其等待中的重启，之后以相同编号获取到的是全新的实例
+pub fn remove(id: InstanceId) {
+    let mut instances = INSTANCES.lock().unwrap_or_else(|x| x.into_inner());
+    if let Some(index) = instances.iter().position(|x| x.id == id) {
+        instances.remove(index).supervisor().cancel_pending();
+    }
+}
+
+pub fn all() -> Vec<Arc<HiperInstance>> {
+    INSTANCES.lock()
+        .map(|x| x.to_owned())
+        .unwrap_or_default()
+}
+
+pub fn is_any_running() -> bool {
+    all()
+        .iter()
+        .any(|x| x.is_running())
+}
+
+/// 进程是否由当前运行中的某个实例启动或接管
+pub fn owns_pid(pid: u32) -> bool {
+    all()
+        .iter()
+        .any(|x| x.pid() == Some(pid))
+}
+
+/// 停止所有实例的 HiPer，用于退出 HiPer Bridge 时
+pub fn stop_all() {
+    for instance in all() {
+        instance.supervisor().cancel_pending();
+        instance.stop_process();
+    }
+}
@@ -0,0 +1,280 @@
+//! 下载文件的完整性校验
+//!
+//! 镜像根目录下的 `packages.sha1` 列出了每个文件的 SHA-1 摘要，格式为 `<摘要>  <架构>/<文件名>`。
+//! 如果镜像同时提供了相同格式的 `packages.sha256`，还会额外校验 SHA-256 摘要。
+//! 校验失败的文件不会被安装，原有的文件保持不变
+//!
+//! 摘要索引和文件来自同一个镜像，因此只能发现下载损坏或镜像不同步，无法防止镜像本身被篡改。
+//! 使用固定公钥校验分离签名目前还没有实现，需要镜像先发布签名文件
+
+use std::{ collections::HashMap, fs::File, io::Read, path::{ Path, PathBuf } };
+
+use anyhow::Context;
+use druid::ExtEventSink;
+use sha2::Digest;
+
+use crate::{ download::DownloadProgress, mirror::MirrorList, utils::path_with_suffix, DynResult };
+
+pub fn sha1_hex(data: &[u8]) -> String {
+    let mut s = sha1_smol::Sha1::default();
+    s.update(data);
+    s.hexdigest()
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    sha2::Sha256
+        ::digest(data)
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+/// 解析 `sha1sum` / `sha256sum` 格式的摘要清单，返回文件路径到摘要的映射
+fn parse_digest_list(data: &str) -> HashMap<String, String> {
+    data.lines()
+        .filter_map(|line| {
+            let (hash, path) = line.trim().split_once("  ")?;
+            Some((path.trim().trim_start_matches('*').to_owned(), hash.trim().to_lowercase()))
+        })
+        .collect()
+}
+
+/// 镜像发布的文件摘要索引
+#[derive(Debug, Clone, Default)]
+pub struct PackageIndex {
+    sha1: HashMap<String, String>,
+    sha256: Option<HashMap<String, String>>,
+}
+
+impl PackageIndex {
+    /// 从镜像的 `dir` 目录下载摘要索引，`packages.sha1` 必须存在，`packages.sha256` 可以不存在
+    pub fn fetch(mirrors: &mut MirrorList, ctx: &ExtEventSink, dir: &str) -> DynResult<Self> {
+        let sha1 = mirrors
+            .fetch(ctx, |mirror| mirror.in_dir(dir).file_url("packages.sha1"))
+            .context("无法获取 HiPer 下载索引")?
+            .as_str()
+            .context("无法解析 HiPer 下载索引")?
+            .to_owned();
+        let sha256 = match mirrors.fetch_optional(|mirror| mirror.in_dir(dir).file_url("packages.sha256")) {
+            Ok(res) => Some(res.as_str().context("无法解析 HiPer SHA-256 下载索引")?.to_owned()),
+            Err(_) => {
+                crate::log_buffer::warn("镜像没有提供 packages.sha256，将只校验 SHA-1 摘要");
+                None
+            }
+        };
+        Ok(Self::parse(&sha1, sha256.as_deref()))
+    }
+
+    /// 从 `packages.sha1` 和可选的 `packages.sha256` 的内容解析摘要索引
+    pub fn parse(sha1: &str, sha256: Option<&str>) -> Self {
+        Self {
+            sha1: parse_digest_list(sha1),
+            sha256: sha256.map(parse_digest_list),
+        }
+    }
+
+    /// 查找指定架构下文件的索引路径，即 `<架构>/<文件名>`，不会匹配子目录或名称相近的文件
+    pub fn find(&self, arch: &str, file_name: &str) -> Option<&str> {
+        self.sha1
+            .get_key_value(&format!("{}/{}", arch, file_name))
+            .map(|(path, _)| path.as_str())
+    }
+
+    pub fn sha1_of(&self, path: &str) -> Option<&str> {
+        self.sha1.get(path).map(|x| x.as_str())
+    }
+
+    /// 校验内存中的数据，任意一个摘要不一致都会返回错误
+    ///
+    /// 索引中没有 SHA-1 摘要的文件无法校验，同样视为失败
+    pub fn verify(&self, path: &str, data: &[u8]) -> DynResult {
+        self.verify_digests(path, &sha1_hex(data), &sha256_hex(data))
+    }
+
+    /// 校验磁盘上的文件，文件会被分块读取，不会整个读入内存
+    pub fn verify_file(&self, path: &str, file: &Path) -> DynResult {
+        let (sha1, sha256) = file_digests(file)?;
+        self.verify_digests(path, &sha1, &sha256)
+    }
+
+    fn verify_digests(&self, path: &str, sha1: &str, sha256: &str) -> DynResult {
+        let expected = self.sha1_of(path).with_context(|| format!("下载索引中没有 {} 的摘要，无法校验", path))?;
+        if sha1 != expected {
+            anyhow::bail!(
+                "{} 的 SHA-1 摘要不一致，文件可能已损坏或被篡改（应为 {}，实际为 {}）",
+                path,
+                expected,
+                sha1
+            );
+        }
+        if let Some(sha256_list) = &self.sha256 {
+            let expected = sha256_list
+                .get(path)
+                .with_context(|| format!("SHA-256 下载索引中没有 {} 的摘要，无法校验", path))?;
+            if sha256 != expected {
+                anyhow::bail!(
+                    "{} 的 SHA-256 摘要不一致，文件可能已损坏或被篡改（应为 {}，实际为 {}）",
+                    path,
+                    expected,
+                    sha256
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 分块计算文件的 SHA-1 和 SHA-256 摘要
+fn file_digests(file: &Path) -> DynResult<(String, String)> {
+    let mut reader = File::open(file).context("无法打开下载的文件以校验摘要")?;
+    let mut sha1 = sha1_smol::Sha1::default();
+    let mut sha256 = sha2::Sha256::default();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let len = reader.read(&mut buf).context("无法读取下载的文件以校验摘要")?;
+        if len == 0 {
+            break;
+        }
+        sha1.update(&buf[..len]);
+        sha256.update(&buf[..len]);
+    }
+    Ok((
+        sha1.hexdigest(),
+        sha256
+            .finalize()
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect(),
+    ))
+}
+
+/// 从镜像下载文件并按下载索引校验，下载索引只会在第一次需要时获取
+pub struct VerifiedDownloader {
+    mirrors: MirrorList,
+    /// 下载索引和文件在镜像中所在的目录，为空时即镜像根目录
+    dir: String,
+    index: Option<PackageIndex>,
+}
+
+impl VerifiedDownloader {
+    pub fn new(mirrors: MirrorList, dir: impl Into<String>) -> Self {
+        Self {
+            mirrors,
+            dir: dir.into(),
+            index: None,
+        }
+    }
+
+    pub fn index(&mut self, ctx: &ExtEventSink) -> DynResult<&PackageIndex> {
+        if self.index.is_none() {
+            self.index = Some(PackageIndex::fetch(&mut self.mirrors, ctx, &self.dir)?);
+        }
+        Ok(self.index.get_or_insert_with(PackageIndex::default))
+    }
+
+    /// 下载并校验文件，`arch` 为 `None` 时文件位于镜像根目录
+    ///
+    /// 文件会被流式下载到 `dest` 旁的 `.download` 临时文件中并返回其路径，由调用方安装到目标位置。
+    /// 下载中断时临时文件会被保留用于下次继续下载；某个镜像的文件校验失败时临时文件会被删除，
+    /// 并尝试下一个镜像。索引中没有该文件的摘要时不会下载，未经校验的文件不会被安装
+    pub fn download(
+        &mut self,
+        ctx: &ExtEventSink,
+        arch: Option<&str>,
+        file_name: &str,
+        dest: &Path,
+        on_progress: &mut dyn FnMut(&DownloadProgress) -> DynResult
+    ) -> DynResult<PathBuf> {
+        self.index(ctx)?;
+        let Self { mirrors, dir, index } = self;
+        let index = index.as_ref().context("无法获取 HiPer 下载索引")?;
+        let path = match arch {
+            Some(arch) => index.find(arch, file_name),
+            None => index.sha1_of(file_name).map(|_| file_name),
+        }.with_context(|| format!("下载索引中没有 {} 的摘要，无法校验", file_name))?;
+        let part_path = path_with_suffix(dest, ".download");
+        mirrors.download_to(
+            ctx,
+            |mirror| {
+                let mirror = mirror.in_dir(dir);
+                match arch {
+                    Some(arch) => mirror.arch_file_url(arch, file_name),
+                    None => mirror.file_url(file_name),
+                }
+            },
+            &part_path,
+            on_progress,
+            |part_path| index.verify_file(path, part_path)
+        )?;
+        Ok(part_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HIPER: &[u8] = b"hiper binary";
+
+    fn sample_index(sha256: Option<&str>) -> PackageIndex {
+        let sha1 = format!(
+            "{}  windows-amd64/old/hiper.exe\n{}  windows-amd64/hiper.exe\n{}  windows-amd64-foo/hiper.exe\n{}  linux-amd64/xhiper\n",
+            sha1_hex(b"old"),
+            sha1_hex(HIPER),
+            sha1_hex(b"foo"),
+            sha1_hex(b"x")
+        );
+        PackageIndex::parse(&sha1, sha256)
+    }
+
+    #[test]
+    fn parse_digest_list_accepts_binary_marker() {
+        let list = parse_digest_list("ABCDEF  a/b\ninvalid line\n\n");
+        assert_eq!(list.get("a/b").map(String::as_str), Some("abcdef"));
+        assert_eq!(list.len(), 1);
+        let list = parse_digest_list("abc  *c/d\n");
+        assert_eq!(list.get("c/d").map(String::as_str), Some("abc"));
+    }
+
+    #[test]
+    fn find_matches_exact_path_only() {
+        let index = sample_index(None);
+        // 同时存在子目录和名称相近的文件时也只会返回完全一致的路径
+        assert_eq!(index.find("windows-amd64", "hiper.exe"), Some("windows-amd64/hiper.exe"));
+        assert_eq!(index.find("linux-amd64", "hiper"), None);
+        assert_eq!(index.find("linux-amd64", "xhiper"), Some("linux-amd64/xhiper"));
+        assert_eq!(index.find("windows-arm64", "hiper.exe"), None);
+        assert_eq!(index.find("windows", "hiper.exe"), None);
+    }
+
+    #[test]
+    fn verify_checks_sha1() {
+        let index = sample_index(None);
+        assert!(index.verify("windows-amd64/hiper.exe", HIPER).is_ok());
+        assert!(index.verify("windows-amd64/hiper.exe", b"tampered").is_err());
+        assert!(index.verify("windows-amd64/missing.exe", HIPER).is_err());
+    }
+
+    #[test]
+    fn verify_checks_sha256_when_present() {
+        let sha256 = format!("{}  windows-amd64/hiper.exe\n", sha256_hex(HIPER));
+        let index = sample_index(Some(&sha256));
+        assert!(index.verify("windows-amd64/hiper.exe", HIPER).is_ok());
+        // SHA-256 索引中没有的文件同样视为校验失败
+        assert!(index.verify("linux-amd64/xhiper", b"x").is_err());
+
+        let wrong = format!("{}  windows-amd64/hiper.exe\n", sha256_hex(b"other"));
+        assert!(sample_index(Some(&wrong)).verify("windows-amd64/hiper.exe", HIPER).is_err());
+    }
+
+    #[test]
+    fn verify_file_matches_verify() {
+        let path = std::env::temp_dir().join(format!("hiper-bridge-integrity-{}", std::process::id()));
+        std::fs::write(&path, HIPER).unwrap();
+        let sha256 = format!("{}  windows-amd64/hiper.exe\n", sha256_hex(HIPER));
+        assert!(sample_index(Some(&sha256)).verify_file("windows-amd64/hiper.exe", &path).is_ok());
+        std::fs::write(&path, b"tampered").unwrap();
+        assert!(sample_index(Some(&sha256)).verify_file("windows-amd64/hiper.exe", &path).is_err());
+        let _ = std::fs::remove_file(path);
+    }
+}
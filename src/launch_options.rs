@@ -0,0 +1,262 @@
+//! HiPer 的启动参数
+//!
+//! 所有传递给 HiPer 的命令行参数都应当通过 [`HiperLaunchOptions`] 生成，
+//! 避免在各处手动拼接参数
+
+use std::{ path::{ Path, PathBuf }, process::Command, time::Duration };
+
+use crate::{
+    app_state::{ AppState, NetworkState },
+    http::ProxyConfig,
+    log_history::LogHistory,
+    mirror::MirrorList,
+    process::ExistingProcessPolicy,
+    update::UpdateOptions,
+};
+
+/// 一次 HiPer 启动所需的全部设置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HiperLaunchOptions {
+    /// 凭证密钥，为空时将使用临时网络
+    pub token: String,
+    /// 使用 WinTUN 而不是 TAP 设备
+    pub use_tun: bool,
+    /// 使用 TCP 传输
+    pub use_tcp: bool,
+    /// 启用多播优化
+    pub use_igmp: bool,
+    /// 优先模式
+    pub fast_mode: bool,
+    /// 调试模式，仅在 Windows 发行版本下会打开调试控制台
+    pub debug_mode: bool,
+    /// 离线模式，不会下载或更新任何文件
+    pub offline_mode: bool,
+    /// 启动前对上次启动且仍在运行的 HiPer 进程的处理方式
+    pub existing_process: ExistingProcessPolicy,
+    /// 自定义的 HiPer 程序路径，设置后将不会下载或更新 HiPer
+    pub binary_path: Option<PathBuf>,
+    /// 原样追加到命令行末尾的额外参数，界面中没有提供的 HiPer 参数可以通过这里传递
+    pub extra_args: Vec<String>,
+    /// 停止 HiPer 时等待其自行退出的时间，超时后将强制结束进程
+    pub stop_timeout: Duration,
+    /// 等待 HiPer 入网的最长时间，为 `None` 时将一直等待
+    pub join_timeout: Option<Duration>,
+    /// 下载 HiPer 相关文件时使用的镜像
+    pub mirrors: MirrorList,
+    /// HiPer 的更新设置
+    pub update: UpdateOptions,
+    /// 所有网络请求使用的代理设置
+    pub proxy: ProxyConfig,
+    /// 历史日志的保留设置
+    pub log_history: LogHistory,
+}
+
+impl Default for HiperLaunchOptions {
+    fn default() -> Self {
+        Self {
+            token: "".into(),
+            use_tun: true,
+            use_tcp: false,
+            use_igmp: false,
+            fast_mode: false,
+            debug_mode: false,
+            offline_mode: false,
+            existing_process: ExistingProcessPolicy::StopOwned,
+            binary_path: None,
+            extra_args: vec![],
+            stop_timeout: Duration::from_secs(5),
+            join_timeout: Some(Duration::from_secs(60)),
+            mirrors: MirrorList::default(),
+            update: UpdateOptions::default(),
+            proxy: ProxyConfig::default(),
+            log_history: LogHistory::default(),
+        }
+    }
+}
+
+impl From<&AppState> for HiperLaunchOptions {
+    fn from(data: &AppState) -> Self {
+        let binary_path = data.hiper_path.trim();
+        Self {
+            token: data.token.trim().to_owned(),
+            use_tun: data.use_tun,
+            use_tcp: data.use_tcp,
+            use_igmp: data.use_igmp,
+            fast_mode: data.fast_mode,
+            debug_mode: data.debug_mode,
+            offline_mode: data.offline_mode,
+            existing_process: data.existing_process_policy,
+            binary_path: if binary_path.is_empty() {
+                None
+            } else {
+                Some(PathBuf::from(binary_path))
+            },
+            extra_args: parse_extra_args(&data.extra_args),
+            stop_timeout: Duration::from_secs(data.stop_timeout_secs),
+            join_timeout: if data.join_timeout_secs == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(data.join_timeout_secs))
+            },
+            mirrors: MirrorList::new(
+                data.mirrors.iter().cloned().collect(),
+                Some(data.last_mirror.to_owned())
+            ),
+            update: UpdateOptions::from(data),
+            proxy: ProxyConfig::from(data),
+            log_history: LogHistory::from(data),
+        }
+    }
+}
+
+/// 将设置页中填写的额外参数按空白字符拆分成参数列表
+pub fn parse_extra_args(args: &str) -> Vec<String> {
+    args.split_whitespace().map(str::to_owned).collect()
+}
+
+impl HiperLaunchOptions {
+    /// 生成其它网络的启动设置，除凭证外沿用主网络的设置
+    pub fn for_network(data: &AppState, network: &NetworkState) -> Self {
+        let mut options = Self::from(data);
+        options.token = network.token.trim().to_owned();
+        options
+    }
+
+    pub fn has_token(&self) -> bool {
+        !self.token.is_empty()
+    }
+
+    /// 生成传递给 HiPer 的完整参数列表，顺序和 HiPer 的命令行解析保持一致
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::with_capacity(8 + self.extra_args.len());
+
+        if self.has_token() {
+            args.push("-g".into());
+            args.push(self.token.to_owned());
+        }
+
+        if !self.use_tun {
+            args.push("-t".into());
+        }
+
+        if !self.use_igmp {
+            args.push("-m".into());
+        }
+
+        if !self.use_tcp {
+            args.push("--tcp".into());
+        }
+
+        if self.fast_mode {
+            args.push("--fast".into());
+        }
+
+        args.extend(self.extra_args.iter().cloned());
+
+        args
+    }
+
+    /// 获取实际需要运行的 HiPer 程序路径，没有自定义路径时使用安装目录内的程序
+    pub fn resolve_binary_path(&self, installed_path: &Path) -> PathBuf {
+        self.binary_path.to_owned().unwrap_or_else(|| installed_path.to_path_buf())
+    }
+
+    /// 根据当前设置生成 HiPer 的启动指令
+    pub fn build_command(&self, hiper_path: impl AsRef<Path>) -> Command {
+        let mut command = Command::new(hiper_path.as_ref());
+        command.args(self.to_args());
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(token: &str, use_tun: bool, use_igmp: bool, use_tcp: bool, fast_mode: bool) -> HiperLaunchOptions {
+        HiperLaunchOptions {
+            token: token.into(),
+            use_tun,
+            use_igmp,
+            use_tcp,
+            fast_mode,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn args_for_every_flag_combination() {
+        for bits in 0..32 {
+            let flag = |bit: u32| bits & (1 << bit) != 0;
+            let token = if flag(0) { "token-123" } else { "" };
+            let options = options(token, flag(1), flag(2), flag(3), flag(4));
+            let mut expected: Vec<&str> = vec![];
+            if flag(0) {
+                expected.extend(["-g", "token-123"]);
+            }
+            if !flag(1) {
+                expected.push("-t");
+            }
+            if !flag(2) {
+                expected.push("-m");
+            }
+            if !flag(3) {
+                expected.push("--tcp");
+            }
+            if flag(4) {
+                expected.push("--fast");
+            }
+            assert_eq!(options.to_args(), expected, "{:05b}", bits);
+        }
+    }
+
+    #[test]
+    fn args_for_common_settings() {
+        let cases: &[(HiperLaunchOptions, &[&str])] = &[
+            (HiperLaunchOptions::default(), &["-m", "--tcp"]),
+            (options("abc", true, true, true, false), &["-g", "abc"]),
+            (options("abc", false, false, false, true), &["-g", "abc", "-t", "-m", "--tcp", "--fast"]),
+            (
+                HiperLaunchOptions {
+                    extra_args: parse_extra_args("  --verbose   --config a.yml "),
+                    ..options("abc", true, true, true, false)
+                },
+                &["-g", "abc", "--verbose", "--config", "a.yml"],
+            ),
+        ];
+        for (options, expected) in cases {
+            assert_eq!(options.to_args(), *expected);
+        }
+    }
+
+    #[test]
+    fn build_command_uses_binary_and_args() {
+        let options = HiperLaunchOptions {
+            extra_args: vec!["--verbose".into()],
+            ..options("abc", true, false, true, true)
+        };
+        let command = options.build_command("/opt/hiper/hiper");
+        assert_eq!(command.get_program(), "/opt/hiper/hiper");
+        let args: Vec<_> = command
+            .get_args()
+            .map(|x| x.to_string_lossy().to_string())
+            .collect();
+        assert_eq!(args, ["-g", "abc", "-m", "--fast", "--verbose"]);
+    }
+
+    #[test]
+    fn network_options_only_replace_token() {
+        let data = AppState {
+            token: "main".into(),
+            extra_args: "--verbose".into(),
+            ..Default::default()
+        };
+        let options = HiperLaunchOptions::for_network(&data, &NetworkState::new(2, " net ".into()));
+        assert_eq!(options.token, "net");
+        let expected = HiperLaunchOptions {
+            token: "net".into(),
+            ..HiperLaunchOptions::from(&data)
+        };
+        assert_eq!(options, expected);
+    }
+}
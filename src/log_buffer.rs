@@ -0,0 +1,323 @@
+//! 日志页中显示的实时日志
+//!
+//! HiPer 的输出和 HiPer Bridge 自身的事件（下载、插件运行、IP 变更等）会按发生顺序写入同一个
+//! 环形缓冲区，只保留最近的 [`CAPACITY`] 行。界面会定时把新增的行同步到 [`AppState::log_lines`]，
+//! 并按筛选条件更新日志页显示的 [`AppState::log_view`]
+
+use std::{ collections::VecDeque, sync::Mutex };
+
+use druid::{ im::Vector, Data };
+
+use crate::{ app_state::AppState, log_parser::LogEvent };
+
+/// 缓冲区中最多保留的行数
+pub const CAPACITY: usize = 2000;
+
+static BUFFER: Mutex<LogBuffer> = Mutex::new(LogBuffer {
+    lines: VecDeque::new(),
+    next_seq: 0,
+});
+
+struct LogBuffer {
+    lines: VecDeque<LogLine>,
+    next_seq: u64,
+}
+
+/// 日志页中的一行
+#[derive(Debug, Clone, PartialEq, Eq, Data)]
+pub struct LogLine {
+    /// 递增的序号，用于判断哪些行还没有同步到界面
+    pub seq: u64,
+    /// 记录的本地时间，格式为 `时:分:秒`
+    pub time: String,
+    /// 日志来源，HiPer 的输出为网络名称，HiPer Bridge 自身的事件为 `bridge`
+    pub source: String,
+    /// 小写的日志等级，无法识别时为空
+    pub level: String,
+    pub text: String,
+}
+
+impl std::fmt::Display for LogLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.level.is_empty() {
+            write!(f, "{} [{}] {}", self.time, self.source, self.text)
+        } else {
+            write!(f, "{} [{}] {}: {}", self.time, self.source, self.level.to_uppercase(), self.text)
+        }
+    }
+}
+
+/// 日志页的等级筛选
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Data)]
+pub enum LogLevelFilter {
+    All,
+    Info,
+    Warning,
+    Error,
+}
+
+/// 日志等级的严重程度，无法识别的等级视为 `info`
+fn severity(level: &str) -> u8 {
+    match level {
+        "trace" | "debug" => 0,
+        "warning" => 2,
+        "error" | "fatal" | "panic" => 3,
+        _ => 1,
+    }
+}
+
+impl LogLevelFilter {
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevelFilter::All => "全部等级",
+            LogLevelFilter::Info => "信息及以上",
+            LogLevelFilter::Warning => "警告及以上",
+            LogLevelFilter::Error => "仅错误",
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            LogLevelFilter::All => LogLevelFilter::Info,
+            LogLevelFilter::Info => LogLevelFilter::Warning,
+            LogLevelFilter::Warning => LogLevelFilter::Error,
+            LogLevelFilter::Error => LogLevelFilter::All,
+        }
+    }
+
+    pub fn matches(self, level: &str) -> bool {
+        let min = match self {
+            LogLevelFilter::All => 0,
+            LogLevelFilter::Info => 1,
+            LogLevelFilter::Warning => 2,
+            LogLevelFilter::Error => 3,
+        };
+        severity(level) >= min
+    }
+}
+
+/// 写入一行日志
+pub fn push(source: impl Into<String>, level: &str, text: impl Into<String>) {
+    if let Ok(mut buffer) = BUFFER.lock() {
+        let seq = buffer.next_seq;
+        buffer.next_seq += 1;
+        if buffer.lines.len() >= CAPACITY {
+            buffer.lines.pop_front();
+        }
+        buffer.lines.push_back(LogLine {
+            seq,
+            time: chrono::Local::now().format("%H:%M:%S").to_string(),
+            source: source.into(),
+            level: level.into(),
+            text: text.into(),
+        });
+    }
+}
+
+/// 写入一条 HiPer 的日志
+pub fn push_event(source: impl Into<String>, event: &LogEvent) {
+    let text = if event.is_structured() {
+        let mut text = event.message.to_owned();
+        if !event.error.is_empty() {
+            text.push_str(&format!(" error={}", event.error));
+        }
+        for (key, value) in &event.fields {
+            text.push_str(&format!(" {}={}", key, value));
+        }
+        text
+    } else {
+        event.raw.to_owned()
+    };
+    push(source, &event.level, text);
+}
+
+/// 记录 HiPer Bridge 自身的一般事件，同时输出到控制台
+pub fn info(text: impl Into<String>) {
+    let text = text.into();
+    println!("[INFO] {}", text);
+    push("bridge", "info", text);
+}
+
+/// 记录 HiPer Bridge 自身的警告，同时输出到控制台
+pub fn warn(text: impl Into<String>) {
+    let text = text.into();
+    println!("[WARN] {}", text);
+    push("bridge", "warning", text);
+}
+
+/// 缓冲区中的所有行，不受日志页的筛选影响
+pub fn all_text() -> String {
+    let mut text = String::new();
+    if let Ok(buffer) = BUFFER.lock() {
+        for line in buffer.lines.iter() {
+            text.push_str(&line.to_string());
+            text.push('\n');
+        }
+    }
+    text
+}
+
+/// 将缓冲区中新增的行同步到界面，超出 [`CAPACITY`] 的旧行会被移除
+pub fn sync(data: &mut AppState) {
+    let last_seq = data.log_lines.last().map(|x| x.seq);
+    let new_lines: Vector<LogLine> = match BUFFER.lock() {
+        Ok(buffer) =>
+            buffer.lines
+                .iter()
+                .filter(|x| last_seq.map(|seq| x.seq > seq).unwrap_or(true))
+                .cloned()
+                .collect(),
+        Err(_) => {
+            return;
+        }
+    };
+    if new_lines.is_empty() {
+        return;
+    }
+    data.log_lines.append(new_lines);
+    while data.log_lines.len() > CAPACITY {
+        data.log_lines.pop_front();
+    }
+    refresh_view(data);
+}
+
+/// 按等级筛选和搜索文字重新生成日志页显示的行，暂停时不包含暂停后新增的行
+pub fn refresh_view(data: &mut AppState) {
+    let search = data.log_search.trim().to_lowercase();
+    data.log_view = data.log_lines
+        .iter()
+        .filter(|line| data.log_level_filter.matches(&line.level))
+        .filter(|line| search.is_empty() || line.to_string().to_lowercase().contains(&search))
+        .cloned()
+        .collect();
+}
+
+/// 点击日志页中序号为 `seq` 的行后的选中范围
+///
+/// `extend` 为 `true`（按住 Shift）时从已选中的起始行选中到该行，再次点击唯一选中的行会取消选中
+pub fn select(selection: Option<(u64, u64)>, seq: u64, extend: bool) -> Option<(u64, u64)> {
+    match selection {
+        Some((start, _)) if extend => Some((start, seq)),
+        Some((start, end)) if start == seq && end == seq => None,
+        _ => Some((seq, seq)),
+    }
+}
+
+/// 序号为 `seq` 的行是否在选中范围内
+pub fn is_selected(selection: Option<(u64, u64)>, seq: u64) -> bool {
+    match selection {
+        Some((start, end)) => start.min(end) <= seq && seq <= start.max(end),
+        None => false,
+    }
+}
+
+/// 日志页“复制”按钮复制的内容，有选中的行时只包含选中且显示中的行，否则包含所有显示中的行
+pub fn copy_text(data: &AppState) -> String {
+    let mut text = String::new();
+    for line in data.log_view.iter() {
+        if data.log_selection.is_some() && !is_selected(data.log_selection, line.seq) {
+            continue;
+        }
+        text.push_str(&line.to_string());
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(seq: u64, level: &str, text: &str) -> LogLine {
+        LogLine {
+            seq,
+            time: "12:00:00".into(),
+            source: "主网络".into(),
+            level: level.into(),
+            text: text.into(),
+        }
+    }
+
+    fn state() -> AppState {
+        let mut data = AppState {
+            log_lines: [
+                line(0, "debug", "handshake sent"),
+                line(1, "", "unstructured output"),
+                line(2, "info", "Handshake message received"),
+                line(3, "warning", "Retrying handshake"),
+                line(4, "error", "Failed to connect"),
+            ]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        refresh_view(&mut data);
+        data
+    }
+
+    fn visible_seqs(data: &AppState) -> Vec<u64> {
+        data.log_view.iter().map(|x| x.seq).collect()
+    }
+
+    #[test]
+    fn view_applies_level_filter() {
+        let mut data = state();
+        let cases = [
+            (LogLevelFilter::All, vec![0, 1, 2, 3, 4]),
+            (LogLevelFilter::Info, vec![1, 2, 3, 4]),
+            (LogLevelFilter::Warning, vec![3, 4]),
+            (LogLevelFilter::Error, vec![4]),
+        ];
+        for (filter, expected) in cases {
+            data.log_level_filter = filter;
+            refresh_view(&mut data);
+            assert_eq!(visible_seqs(&data), expected, "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn view_applies_search() {
+        let mut data = state();
+        data.log_level_filter = LogLevelFilter::Info;
+        data.log_search = "  HANDSHAKE ".into();
+        refresh_view(&mut data);
+        assert_eq!(visible_seqs(&data), [2, 3]);
+        assert_eq!(
+            copy_text(&data),
+            "12:00:00 [主网络] INFO: Handshake message received\n12:00:00 [主网络] WARNING: Retrying handshake\n"
+        );
+
+        data.log_search = "no such line".into();
+        refresh_view(&mut data);
+        assert_eq!(copy_text(&data), "");
+    }
+
+    #[test]
+    fn select_single_line_and_range() {
+        let selection = select(None, 2, false);
+        assert_eq!(selection, Some((2, 2)));
+        // 再次点击同一行取消选中
+        assert_eq!(select(selection, 2, false), None);
+        assert_eq!(select(selection, 3, false), Some((3, 3)));
+        // 按住 Shift 时可以向前或向后扩展
+        let selection = select(selection, 0, true);
+        assert_eq!(selection, Some((2, 0)));
+        assert!([0, 1, 2].iter().all(|x| is_selected(selection, *x)));
+        assert!(!is_selected(selection, 3));
+        assert_eq!(select(None, 4, true), Some((4, 4)));
+    }
+
+    #[test]
+    fn copy_text_uses_selection() {
+        let mut data = state();
+        data.log_selection = Some((3, 1));
+        assert_eq!(
+            copy_text(&data),
+            "12:00:00 [主网络] unstructured output\n12:00:00 [主网络] INFO: Handshake message received\n12:00:00 [主网络] WARNING: Retrying handshake\n"
+        );
+        // 被筛选掉的行即使在选中范围内也不会被复制
+        data.log_level_filter = LogLevelFilter::Warning;
+        refresh_view(&mut data);
+        assert_eq!(copy_text(&data), "12:00:00 [主网络] WARNING: Retrying handshake\n");
+    }
+}
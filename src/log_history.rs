@@ -0,0 +1,233 @@
+//! HiPer 日志的历史记录
+//!
+//! 每次启动 HiPer 前，上一次运行的 `latest.log` 会被移动到工作目录下的 `logs` 文件夹中，
+//! 并以结束时间命名，例如 `hiper-20221001-120000.log`。除最近一份外，更早的日志都会被
+//! gzip 压缩，超出保留数量或总大小上限的最旧的日志会被删除
+
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{ Path, PathBuf },
+};
+
+use anyhow::Context;
+use chrono::{ DateTime, Local, NaiveDateTime };
+
+use crate::{ app_state::AppState, DynResult };
+
+/// 历史日志的文件名前缀
+const FILE_PREFIX: &str = "hiper-";
+/// 历史日志文件名中结束时间的格式
+const TIME_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+/// 历史日志的保留设置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogHistory {
+    /// 保留的历史日志份数，为 0 时不保留历史日志
+    pub keep: usize,
+    /// 所有历史日志的总大小上限（字节），为 0 时不限制
+    pub max_total_size: u64,
+}
+
+impl Default for LogHistory {
+    fn default() -> Self {
+        Self {
+            keep: 10,
+            max_total_size: 50 * 1024 * 1024,
+        }
+    }
+}
+
+impl From<&AppState> for LogHistory {
+    fn from(data: &AppState) -> Self {
+        Self {
+            keep: data.log_history_count as usize,
+            max_total_size: data.log_history_max_mb * 1024 * 1024,
+        }
+    }
+}
+
+/// 历史日志所在的文件夹
+pub fn history_dir(log_path: &Path) -> PathBuf {
+    log_path.with_file_name("logs")
+}
+
+/// 按时间从新到旧列出所有历史日志
+pub fn list_history(log_path: &Path) -> Vec<PathBuf> {
+    let mut logs: Vec<PathBuf> = std::fs::read_dir(history_dir(log_path))
+        .map(|dir| {
+            dir.filter_map(|x| x.ok())
+                .map(|x| x.path())
+                .filter(|x| {
+                    let name = x.file_name().unwrap_or_default().to_string_lossy();
+                    name.starts_with(FILE_PREFIX) && (name.ends_with(".log") || name.ends_with(".log.gz"))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    logs.sort_by_cached_key(|x| std::cmp::Reverse(history_key(x)));
+    logs
+}
+
+/// 历史日志的排序依据：文件名中的结束时间和同一秒内的序号，例如 `hiper-20221001-120000-2.log`，
+/// 无法解析文件名时使用文件的修改时间
+///
+/// 不能直接按文件名排序，否则 `-10` 会排在 `-2` 之前，没有序号的日志也会排在有序号的之后
+fn history_key(path: &Path) -> (Option<NaiveDateTime>, u32, PathBuf) {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let parsed = name
+        .strip_suffix(".log.gz")
+        .or_else(|| name.strip_suffix(".log"))
+        .and_then(|x| x.strip_prefix(FILE_PREFIX))
+        .and_then(|x| {
+            let (time, index) = (x.get(..15)?, x.get(15..)?);
+            let index = if index.is_empty() { 0 } else { index.strip_prefix('-')?.parse().ok()? };
+            Some((NaiveDateTime::parse_from_str(time, TIME_FORMAT).ok()?, index))
+        });
+    let (time, index) = match parsed {
+        Some((time, index)) => (Some(time), index),
+        None => {
+            let modified = path
+                .metadata()
+                .and_then(|x| x.modified())
+                .ok()
+                .map(|x| DateTime::<Local>::from(x).naive_local());
+            (modified, 0)
+        }
+    };
+    (time, index, path.to_path_buf())
+}
+
+/// 将上一次运行的日志移入历史记录，并压缩和清理更早的日志，需要在重新打开 `log_path` 之前调用
+pub fn rotate(log_path: &Path, history: LogHistory) -> DynResult {
+    if history.keep == 0 {
+        for log in list_history(log_path) {
+            let _ = std::fs::remove_file(log);
+        }
+        return Ok(());
+    }
+
+    let has_log = log_path
+        .metadata()
+        .map(|x| x.len() > 0)
+        .unwrap_or(false);
+    if has_log {
+        let dir = history_dir(log_path);
+        std::fs::create_dir_all(&dir).context("无法创建历史日志文件夹")?;
+        let ended_at: DateTime<Local> = log_path
+            .metadata()
+            .and_then(|x| x.modified())
+            .map(DateTime::from)
+            .unwrap_or_else(|_| Local::now());
+        let name = format!("{}{}", FILE_PREFIX, ended_at.format(TIME_FORMAT));
+        let mut dest = dir.join(format!("{}.log", name));
+        let mut index = 1;
+        while dest.exists() || dest.with_extension("log.gz").exists() {
+            dest = dir.join(format!("{}-{}.log", name, index));
+            index += 1;
+        }
+        std::fs::rename(log_path, &dest).context("无法移动上一次运行的日志")?;
+    }
+
+    let logs = list_history(log_path);
+    for log in logs.iter().skip(1) {
+        if log.extension().map(|x| x == "log").unwrap_or(false) {
+            if let Err(err) = compress(log) {
+                crate::log_buffer::warn(format!("无法压缩历史日志 {}：{:?}", log.display(), err));
+            }
+        }
+    }
+
+    let mut total_size = 0;
+    for (i, log) in list_history(log_path).into_iter().enumerate() {
+        total_size += log
+            .metadata()
+            .map(|x| x.len())
+            .unwrap_or(0);
+        let over_size = history.max_total_size > 0 && total_size > history.max_total_size;
+        // 至少保留最近的一份，即使它本身已经超过了大小上限
+        if i >= history.keep || (i > 0 && over_size) {
+            let _ = std::fs::remove_file(&log);
+        }
+    }
+    Ok(())
+}
+
+/// 将日志压缩为同名的 `.log.gz` 文件并删除原文件
+fn compress(log: &Path) -> DynResult {
+    let dest = log.with_extension("log.gz");
+    let mut input = File::open(log)?;
+    let output = BufWriter::new(File::create(&dest)?);
+    let result = (|| -> DynResult {
+        let mut encoder = libflate::gzip::Encoder::new(output)?;
+        std::io::copy(&mut input, &mut encoder)?;
+        encoder.finish().into_result()?.into_inner()?.sync_all()?;
+        Ok(())
+    })();
+    match result {
+        Ok(()) => {
+            std::fs::remove_file(log)?;
+            Ok(())
+        }
+        Err(err) => {
+            let _ = std::fs::remove_file(&dest);
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_key_parses_time_and_index() {
+        let time = |x: &str| NaiveDateTime::parse_from_str(x, TIME_FORMAT).ok();
+        let cases = [
+            ("hiper-20221001-120000.log", time("20221001-120000"), 0),
+            ("hiper-20221001-120000-2.log.gz", time("20221001-120000"), 2),
+            ("hiper-20221001-120000-10.log", time("20221001-120000"), 10),
+        ];
+        for (name, time, index) in cases {
+            let key = history_key(Path::new(name));
+            assert_eq!((key.0, key.1), (time, index), "{}", name);
+        }
+        // 文件不存在且无法解析时没有时间，排在最旧的位置
+        assert_eq!(history_key(Path::new("hiper-broken.log")).0, None);
+        assert_eq!(history_key(Path::new("hiper-20221001-120000-x.log")).0, None);
+    }
+
+    #[test]
+    fn list_history_orders_by_time_and_index() {
+        let dir = std::env::temp_dir().join(format!("hiper-bridge-history-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let log_path = dir.join("latest.log");
+        std::fs::create_dir_all(history_dir(&log_path)).unwrap();
+        let names = [
+            "hiper-20221001-120000.log.gz",
+            "hiper-20221001-120000-1.log.gz",
+            "hiper-20221001-120000-2.log.gz",
+            "hiper-20221001-120000-10.log.gz",
+            "hiper-20220930-235959-3.log.gz",
+            "hiper-20221002-080000.log",
+            "other.log",
+        ];
+        for name in names {
+            std::fs::write(history_dir(&log_path).join(name), b"log").unwrap();
+        }
+
+        let listed: Vec<String> = list_history(&log_path)
+            .iter()
+            .map(|x| x.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(listed, [
+            "hiper-20221002-080000.log",
+            "hiper-20221001-120000-10.log.gz",
+            "hiper-20221001-120000-2.log.gz",
+            "hiper-20221001-120000-1.log.gz",
+            "hiper-20221001-120000.log.gz",
+            "hiper-20220930-235959-3.log.gz",
+        ]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
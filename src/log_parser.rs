@@ -1,32 +1,522 @@
+//! HiPer 日志的解析
+//!
+//! HiPer 的每一行输出都会被解析为一个 [`LogEvent`]，支持以下几种格式：
+//!
+//! - JSON：`{"level":"info","msg":"...","time":"...","vpnIp":"..."}`
+//! - logfmt：`time="..." level=info msg="..." vpnIp=...`
+//! - 终端文本：`INFO[0000] ...                 vpnIp=...`
+//!
+//! 无法识别的行同样会生成事件，此时只有 `message` 和 `raw`
+
+use std::{ collections::BTreeMap, fmt::Display, net::IpAddr, str::FromStr };
+
+use chrono::{ DateTime, FixedOffset };
 use tinyjson::*;
 
-pub fn try_get_log_line(line: &str) -> Option<(String, String, String)> {
-    if let Ok(JsonValue::Object(log_data)) = line.parse::<JsonValue>() {
-        let level;
-        if let Some(JsonValue::String(level_data)) = log_data.get("level") {
-            level = level_data.to_owned();
-        } else {
-            return None;
+/// 一条 HiPer 日志
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LogEvent {
+    /// 小写的日志等级，例如 `info`、`warning`、`error`，无法识别时为空
+    pub level: String,
+    /// 日志的时间，终端文本格式和无法识别的行没有时间
+    pub time: Option<DateTime<FixedOffset>>,
+    pub message: String,
+    /// 日志附带的错误信息，没有时为空
+    pub error: String,
+    /// 除等级、时间、消息和错误以外的其它字段
+    pub fields: BTreeMap<String, String>,
+    /// 原始的日志行
+    pub raw: String,
+}
+
+impl LogEvent {
+    /// 解析一行日志，总是会返回一个事件
+    pub fn parse(line: &str) -> Self {
+        let line = line.trim();
+        Self::from_json(line)
+            .or_else(|| Self::from_text(line))
+            .or_else(|| Self::from_logfmt(line))
+            .unwrap_or_else(|| Self {
+                message: line.to_owned(),
+                raw: line.to_owned(),
+                ..Default::default()
+            })
+    }
+
+    /// 是否成功解析出了日志等级
+    pub fn is_structured(&self) -> bool {
+        !self.level.is_empty()
+    }
+
+    /// 按字段名将字段放到对应的位置
+    fn insert(&mut self, key: &str, value: String) {
+        match key {
+            "level" => {
+                self.level = normalize_level(&value);
+            }
+            "time" => {
+                self.time = DateTime::parse_from_rfc3339(&value).ok();
+            }
+            "msg" => {
+                self.message = value;
+            }
+            "error" => {
+                self.error = value;
+            }
+            _ => {
+                self.fields.insert(key.to_owned(), value);
+            }
         }
-        let error = if let Some(JsonValue::String(error_data)) = log_data.get("error") {
-            error_data.to_owned()
-        } else {
-            "".into()
+    }
+
+    fn from_json(line: &str) -> Option<Self> {
+        let data = match line.parse::<JsonValue>() {
+            Ok(JsonValue::Object(data)) => data,
+            _ => {
+                return None;
+            }
+        };
+        let mut event = Self {
+            raw: line.to_owned(),
+            ..Default::default()
+        };
+        for (key, value) in data {
+            let value = match value {
+                JsonValue::String(value) => value,
+                JsonValue::Null => "".into(),
+                value => value.stringify().unwrap_or_default(),
+            };
+            event.insert(&key, value);
+        }
+        Some(event).filter(|x| x.is_structured())
+    }
+
+    fn from_logfmt(line: &str) -> Option<Self> {
+        let mut event = Self {
+            raw: line.to_owned(),
+            ..Default::default()
         };
-        if let Some(JsonValue::String(message_data)) = log_data.get("msg") {
-            return Some((level, message_data.to_owned(), error));
+        for (key, value) in logfmt_pairs(line) {
+            // logfmt 中的每一项都应当是键值对
+            event.insert(&key, value?);
+        }
+        Some(event).filter(|x| x.is_structured())
+    }
+
+    /// 解析终端文本格式，等级和时间之后是消息，消息之后是 logfmt 格式的字段
+    fn from_text(line: &str) -> Option<Self> {
+        let (level, rest) = line.split_once('[')?;
+        let level = match level {
+            "TRAC" => "trace",
+            "DEBU" => "debug",
+            "INFO" => "info",
+            "WARN" => "warning",
+            "ERRO" => "error",
+            "FATA" => "fatal",
+            "PANI" => "panic",
+            _ => {
+                return None;
+            }
+        };
+        let (_, rest) = rest.split_once(']')?;
+        let mut event = Self {
+            level: level.into(),
+            raw: line.to_owned(),
+            ..Default::default()
+        };
+        let mut message = vec![];
+        for (key, value) in logfmt_pairs(rest) {
+            match value {
+                Some(value) if !key.is_empty() => event.insert(&key, value),
+                _ if event.fields.is_empty() && event.error.is_empty() => message.push(key),
+                _ => {}
+            }
+        }
+        event.message = message.join(" ");
+        Some(event)
+    }
+}
+
+fn normalize_level(level: &str) -> String {
+    match level.to_ascii_lowercase().as_str() {
+        "warn" => "warning".into(),
+        "err" => "error".into(),
+        level => level.to_owned(),
+    }
+}
+
+/// 将 logfmt 格式拆分为键值对，没有 `=` 的词会作为值为 `None` 的键返回
+fn logfmt_pairs(line: &str) -> Vec<(String, Option<String>)> {
+    let mut pairs = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|x| x.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|x| !x.is_whitespace() && *x != '=') {
+            key.push(c);
+        }
+        if chars.next_if_eq(&'=').is_none() {
+            pairs.push((key, None));
+            continue;
+        }
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => {
+                        break;
+                    }
+                    '\\' =>
+                        match chars.next() {
+                            Some('n') => value.push('\n'),
+                            Some('t') => value.push('\t'),
+                            Some(c) => value.push(c),
+                            None => {}
+                        }
+                    c => value.push(c),
+                }
+            }
         } else {
-            return None;
+            while let Some(c) = chars.next_if(|x| !x.is_whitespace()) {
+                value.push(c);
+            }
         }
+        pairs.push((key, Some(value)));
     }
-    None
+    pairs
 }
 
-pub fn try_get_ipv4(line: &str) -> Option<String> {
-    if line.contains("ip:") {
-        let virtual_ip = line.split("ip: ").last().unwrap();
-        let ip = virtual_ip.split(" ").next().unwrap();
-        return Some(ip.to_owned());
+/// HiPer 分配到的虚拟地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualAddress {
+    pub ip: IpAddr,
+    /// 虚拟网络的前缀长度，日志中没有提供时为 `None`
+    pub prefix: Option<u8>,
+}
+
+impl Display for VirtualAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.prefix {
+            Some(prefix) => write!(f, "{}/{}", self.ip, prefix),
+            None => write!(f, "{}", self.ip),
+        }
+    }
+}
+
+impl FromStr for VirtualAddress {
+    type Err = ();
+
+    /// 解析 `10.0.0.1`、`10.0.0.1/24`、`fd00::1/64` 或 `[fd00::1]` 格式的地址
+    ///
+    /// `0.0.0.0` 和 `::` 不是可用的虚拟地址，同样视为无法解析
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value
+            .trim()
+            .trim_end_matches([',', ';', '.'])
+            .trim_start_matches('[');
+        let (ip, prefix) = match value.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix.parse::<u8>().map_err(|_| ())?)),
+            None => (value, None),
+        };
+        let ip = ip.trim_end_matches(']').parse::<IpAddr>().map_err(|_| ())?;
+        if ip.is_unspecified() {
+            return Err(());
+        }
+        let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+        if prefix.map(|x| x > max_prefix).unwrap_or(false) {
+            return Err(());
+        }
+        Ok(Self { ip, prefix })
+    }
+}
+
+/// 从日志中找出 HiPer 分配到的虚拟地址
+///
+/// 地址可以是结构化日志中的 `ip` 字段，也可以是消息中 `ip:` 之后的词，例如：
+///
+/// - `ip: 10.26.0.12`
+/// - `ip: 10.26.0.12/16 mtu: 1420`
+/// - `ip:fd00:26::c/64`
+/// - `{"level":"info","msg":"virtual address assigned","ip":"10.26.0.12/16"}`
+///
+/// `vpnIp: 10.26.0.1` 等名称中包含 `ip:` 的其它字段、缺少地址的 `ip:` 以及无法解析的地址都会被忽略
+pub fn parse_virtual_address(event: &LogEvent) -> Option<VirtualAddress> {
+    if let Some(address) = event.fields.get("ip") {
+        return address.parse().ok();
+    }
+    let mut words = event.message.split_whitespace();
+    while let Some(word) = words.next() {
+        let rest = match word.get(..3) {
+            Some(name) if name.eq_ignore_ascii_case("ip:") => &word[3..],
+            _ => {
+                continue;
+            }
+        };
+        let address = if rest.is_empty() { words.next()? } else { rest };
+        return address.parse().ok();
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// HiPer 输出的日志行和其中的虚拟地址
+    const ADDRESS_FIXTURES: &[(&str, Option<&str>)] = &[
+        ("ip: 10.26.0.12", Some("10.26.0.12")),
+        ("ip: 10.26.0.12/16 mtu: 1420", Some("10.26.0.12/16")),
+        ("IP: 10.26.0.12/16", Some("10.26.0.12/16")),
+        ("ip:10.26.0.12", Some("10.26.0.12")),
+        ("ip: fd00:26::c/64", Some("fd00:26::c/64")),
+        ("ip:fd00:26::c", Some("fd00:26::c")),
+        ("ip: [fd00:26::c]", Some("fd00:26::c")),
+        ("ip: 10.26.0.12, mtu: 1420", Some("10.26.0.12")),
+        (
+            r#"{"level":"info","msg":"virtual address assigned","ip":"10.26.0.12/16","time":"2022-10-01T12:00:00+08:00"}"#,
+            Some("10.26.0.12/16"),
+        ),
+        (r#"time="2022-10-01T12:00:00+08:00" level=info msg="tun device up" ip=10.26.0.12/16"#, Some("10.26.0.12/16")),
+        // 格式不正确或带有多余内容的地址
+        ("ip: 10.26.0.256", None),
+        ("ip: 10.26.0.12/33", None),
+        ("ip: fd00::1/129", None),
+        ("ip: 10.26.0.12abc", None),
+        ("ip: 10.26.0.12/16/8", None),
+        ("ip: 10.26.0", None),
+        (r#"{"level":"info","msg":"virtual address assigned","ip":"not-an-ip"}"#, None),
+        // 未指定的地址不是可用的虚拟地址
+        ("ip: 0.0.0.0", None),
+        ("ip: 0.0.0.0/0", None),
+        ("ip: ::", None),
+        // 不包含虚拟地址的日志
+        ("ip:", None),
+        ("INFO[0000] Main HostMap created                          network=10.26.0.12/16 preferredRanges=\"[]\"", None),
+        (
+            "INFO[0001] Handshake message sent                         handshake=\"map[stage:1 style:ix_psk0]\" udpAddr=\"1.2.3.4:4242\" vpnIp=10.26.0.1",
+            None,
+        ),
+        ("vpnIp: 10.26.0.1", None),
+        ("Firewall started", None),
+        ("", None),
+    ];
+
+    /// 解析结果的期望值：等级、消息、错误和其它字段
+    struct Expected {
+        level: &'static str,
+        message: &'static str,
+        error: &'static str,
+        fields: &'static [(&'static str, &'static str)],
+        has_time: bool,
+    }
+
+    /// HiPer 三种格式的输出和无法识别的行
+    const EVENT_FIXTURES: &[(&str, Expected)] = &[
+        (
+            r#"{"level":"info","msg":"Firewall started","time":"2022-10-01T12:00:00+08:00","firewallHash":"21716b47a7a140e448077fe66c31b4b42f232e996818d7dd1c6f4991e066dbdb"}"#,
+            Expected {
+                level: "info",
+                message: "Firewall started",
+                error: "",
+                fields: &[("firewallHash", "21716b47a7a140e448077fe66c31b4b42f232e996818d7dd1c6f4991e066dbdb")],
+                has_time: true,
+            },
+        ),
+        (
+            r#"{"error":"listen udp4 0.0.0.0:4242: bind: address already in use","level":"fatal","msg":"Failed to open udp listener","time":"2022-10-01T12:00:00+08:00"}"#,
+            Expected {
+                level: "fatal",
+                message: "Failed to open udp listener",
+                error: "listen udp4 0.0.0.0:4242: bind: address already in use",
+                fields: &[],
+                has_time: true,
+            },
+        ),
+        (
+            r#"{"level":"warn","msg":"Refusing to handshake with myself","vpnIp":"10.26.0.12","port":4242,"relay":null}"#,
+            Expected {
+                level: "warning",
+                message: "Refusing to handshake with myself",
+                error: "",
+                fields: &[
+                    ("port", "4242"),
+                    ("relay", ""),
+                    ("vpnIp", "10.26.0.12"),
+                ],
+                has_time: false,
+            },
+        ),
+        (
+            r#"time="2022-10-01T12:00:00+08:00" level=info msg="Handshake message received" certName=host fingerprint=a1b2 initiatorIndex=3705549893 remoteIndex=0 udpAddr="1.2.3.4:4242" vpnIp=10.26.0.1"#,
+            Expected {
+                level: "info",
+                message: "Handshake message received",
+                error: "",
+                fields: &[
+                    ("certName", "host"),
+                    ("fingerprint", "a1b2"),
+                    ("initiatorIndex", "3705549893"),
+                    ("remoteIndex", "0"),
+                    ("udpAddr", "1.2.3.4:4242"),
+                    ("vpnIp", "10.26.0.1"),
+                ],
+                has_time: true,
+            },
+        ),
+        (
+            r#"time="2022-10-01T12:00:00+08:00" level=error msg="Failed to get a tun/tap device" error="operation not permitted: \"/dev/net/tun\"""#,
+            Expected {
+                level: "error",
+                message: "Failed to get a tun/tap device",
+                error: "operation not permitted: \"/dev/net/tun\"",
+                fields: &[],
+                has_time: true,
+            },
+        ),
+        (
+            r#"INFO[0000] Main HostMap created                          network=10.26.0.12/16 preferredRanges="[192.168.0.0/16 10.0.0.0/8]""#,
+            Expected {
+                level: "info",
+                message: "Main HostMap created",
+                error: "",
+                fields: &[
+                    ("network", "10.26.0.12/16"),
+                    ("preferredRanges", "[192.168.0.0/16 10.0.0.0/8]"),
+                ],
+                has_time: false,
+            },
+        ),
+        (
+            r#"ERRO[0003] Failed to send handshake message   error="sendto: network is unreachable" udpAddr="1.2.3.4:4242""#,
+            Expected {
+                level: "error",
+                message: "Failed to send handshake message",
+                error: "sendto: network is unreachable",
+                fields: &[("udpAddr", "1.2.3.4:4242")],
+                has_time: false,
+            },
+        ),
+        (
+            "WARN[0010] Tunnel status                                  tunnelCheck=\"map[method:passive state:dead]\" vpnIp=10.26.0.1",
+            Expected {
+                level: "warning",
+                message: "Tunnel status",
+                error: "",
+                fields: &[
+                    ("tunnelCheck", "map[method:passive state:dead]"),
+                    ("vpnIp", "10.26.0.1"),
+                ],
+                has_time: false,
+            },
+        ),
+        // 以 `{` 开头但不是合法 JSON 的行，以及无法识别的行
+        (
+            r#"{"level":"info","msg":"truncated"#,
+            Expected {
+                level: "",
+                message: r#"{"level":"info","msg":"truncated"#,
+                error: "",
+                fields: &[],
+                has_time: false,
+            },
+        ),
+        (
+            r#"{"msg":"no level"}"#,
+            Expected {
+                level: "",
+                message: r#"{"msg":"no level"}"#,
+                error: "",
+                fields: &[],
+                has_time: false,
+            },
+        ),
+        (
+            "ip: 10.26.0.12/16",
+            Expected {
+                level: "",
+                message: "ip: 10.26.0.12/16",
+                error: "",
+                fields: &[],
+                has_time: false,
+            },
+        ),
+        (
+            "panic: runtime error: invalid memory address or nil pointer dereference",
+            Expected {
+                level: "",
+                message: "panic: runtime error: invalid memory address or nil pointer dereference",
+                error: "",
+                fields: &[],
+                has_time: false,
+            },
+        ),
+        (
+            "INFO something without brackets",
+            Expected {
+                level: "",
+                message: "INFO something without brackets",
+                error: "",
+                fields: &[],
+                has_time: false,
+            },
+        ),
+    ];
+
+    #[test]
+    fn log_event_fixtures() {
+        for (line, expected) in EVENT_FIXTURES {
+            let event = LogEvent::parse(line);
+            assert_eq!(event.level, expected.level, "{}", line);
+            assert_eq!(event.message, expected.message, "{}", line);
+            assert_eq!(event.error, expected.error, "{}", line);
+            let fields: Vec<(&str, &str)> = event.fields
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect();
+            assert_eq!(fields, expected.fields, "{}", line);
+            assert_eq!(event.time.is_some(), expected.has_time, "{}", line);
+            assert_eq!(event.raw, *line);
+            assert_eq!(event.is_structured(), !expected.level.is_empty(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn log_event_time_keeps_offset() {
+        let event = LogEvent::parse(r#"time="2022-10-01T12:00:00+08:00" level=info msg="Firewall started""#);
+        assert_eq!(event.time.unwrap().to_rfc3339(), "2022-10-01T12:00:00+08:00");
+    }
+
+    #[test]
+    fn logfmt_pairs_handle_quotes_and_bare_words() {
+        assert_eq!(logfmt_pairs(r#"msg="a b\"c" key=value bare"#), vec![
+            ("msg".to_owned(), Some(r#"a b"c"#.to_owned())),
+            ("key".to_owned(), Some("value".to_owned())),
+            ("bare".to_owned(), None),
+        ]);
+        assert_eq!(logfmt_pairs("empty="), vec![("empty".to_owned(), Some("".to_owned()))]);
+    }
+
+    #[test]
+    fn virtual_address_fixtures() {
+        for (line, expected) in ADDRESS_FIXTURES {
+            let address = parse_virtual_address(&LogEvent::parse(line)).map(|x| x.to_string());
+            assert_eq!(address.as_deref(), *expected, "{}", line);
+        }
+    }
+
+    #[test]
+    fn virtual_address_keeps_ip_and_prefix() {
+        let address: VirtualAddress = "10.26.0.12/16".parse().unwrap();
+        assert_eq!(address.ip, "10.26.0.12".parse::<IpAddr>().unwrap());
+        assert_eq!(address.prefix, Some(16));
+        let address: VirtualAddress = "[fd00:26::c]/64".parse().unwrap();
+        assert!(address.ip.is_ipv6());
+        assert_eq!(address.prefix, Some(64));
+        assert_eq!("10.26.0.12.".parse::<VirtualAddress>().unwrap().prefix, None);
+        assert!("".parse::<VirtualAddress>().is_err());
+        assert!("10.26.0.12/".parse::<VirtualAddress>().is_err());
+    }
+}
@@ -4,17 +4,39 @@ use std::{ sync::{ Arc, Mutex }, time::Instant };
 
 use config::{ load_config, save_config };
 use druid::{ commands::{ CLOSE_ALL_WINDOWS, CONFIGURE_WINDOW, QUIT_APP }, WidgetExt as _, * };
-use hiper::run_hiper_in_thread;
+use instance::PRIMARY_INSTANCE;
+use launch_options::HiperLaunchOptions;
 use scl_gui_widgets::{ widgets::*, WidgetExt as _ };
+use stage::LaunchStage;
+use supervisor::RestartPolicy;
 
 mod app_state;
+mod bundle;
 mod config;
+mod diagnostics;
+mod download;
+mod failure;
 mod hiper;
+mod hiper_version;
+mod http;
 mod icons;
+mod instance;
+mod integrity;
+mod launch_options;
+mod log_buffer;
+mod log_history;
 mod log_parser;
+mod mirror;
 mod open_url;
 mod plugin;
+mod process;
+mod self_update;
+mod stage;
+mod supervisor;
+#[cfg(test)]
+mod test_server;
 mod ui;
+mod update;
 mod utils;
 #[cfg(target_os = "macos")]
 mod mac;
@@ -37,14 +59,37 @@ fn main() {
     let mut state = AppState::default();
 
     load_config(&mut state);
+    http::set_proxy(http::ProxyConfig::from(&state));
+
+    // HiPerBridge --diagnostics [路径]：导出诊断信息后直接退出
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("--diagnostics") {
+        let path = args.next().unwrap_or_else(diagnostics::default_file_name);
+        match diagnostics::export(path.as_ref(), &state) {
+            Ok(()) => println!("Diagnostics exported to {}", path),
+            Err(err) => println!("Failed to export diagnostics: {:?}", err),
+        }
+        return;
+    }
+    self_update::remove_old_binary();
 
     let size = (295.0, 232.0 + 32.0);
 
     plugin::dispatch_event_and_wait("hb-launch");
 
+    let options = HiperLaunchOptions::from(&state);
+    let mut installed_hiper_path = options.binary_path
+        .to_owned()
+        .or_else(|| hiper::get_hiper_dir().ok().map(|x| x.join(hiper::HIPER_FILE_NAME)));
+    let mut bridge_update = if state.bridge_auto_update && !state.offline_mode {
+        Some((options.mirrors, state.update_channel))
+    } else {
+        None
+    };
     let saved_app_state = Arc::new(Mutex::new(state));
     loop {
         let saved_app_state_c = saved_app_state.clone();
+        let saved_app_state_r = saved_app_state.clone();
         let cloned_app_state = { saved_app_state.lock().unwrap().to_owned() };
 
         let app = AppLauncher::with_window({
@@ -57,38 +102,125 @@ fn main() {
                             )
                         );
                     })
-                    .on_command(SET_DISABLED, |_, disabled, data| {
-                        data.disabled = *disabled;
+                    .on_command(SET_DISABLED, |_, (id, disabled), data| {
+                        if *id == PRIMARY_INSTANCE {
+                            data.disabled = *disabled;
+                        } else if let Some(network) = data.network_mut(*id) {
+                            network.disabled = *disabled;
+                        }
                     })
-                    .on_command(SET_START_TEXT, |_, text, data| {
-                        data.start_button = *text;
+                    .on_command(SET_STAGE, |_, (id, state), data| {
+                        if *id == PRIMARY_INSTANCE {
+                            data.connection = state.to_owned();
+                        } else if let Some(network) = data.network_mut(*id) {
+                            network.connection = state.to_owned();
+                        }
+                    })
+                    .on_command(SET_IP, |_ctx, (id, ip), data| {
+                        // 只在首次入网时重新计时，运行中 IP 地址变化不会清空运行时间
+                        let first_join = |current: &str| current.is_empty() && !ip.is_empty();
+                        if *id == PRIMARY_INSTANCE {
+                            if first_join(&data.ip) {
+                                data.run_time = 0;
+                            }
+                            data.ip = ip.to_owned();
+                        } else if let Some(network) = data.network_mut(*id) {
+                            if first_join(&network.ip) {
+                                network.run_time = 0;
+                            }
+                            network.ip = ip.to_owned();
+                        }
+                    })
+                    .on_command(SET_WARNING, |_, (id, warning), data| {
+                        if *id == PRIMARY_INSTANCE {
+                            data.warning = warning.to_owned();
+                        } else if let Some(network) = data.network_mut(*id) {
+                            network.warning = warning.to_owned();
+                        }
+                    })
+                    .on_command(commands::OPEN_FILE, |ctx, file, _| {
+                        bundle::import_bundle_in_thread(
+                            ctx.get_external_handle(),
+                            file.path().to_path_buf()
+                        );
+                    })
+                    .on_command(commands::SAVE_FILE_AS, |ctx, file, data| {
+                        diagnostics::export_in_thread(
+                            ctx.get_external_handle(),
+                            file.path().to_path_buf(),
+                            data.to_owned()
+                        );
                     })
-                    .on_command(SET_IP, |_ctx, ip, data| {
-                        data.ip = ip.to_owned();
+                    .on_command(SET_LAST_MIRROR, |_, url, data| {
+                        data.last_mirror = url.to_owned();
                     })
-                    .on_command(SET_WARNING, |_, warning, data| {
-                        data.warning = warning.to_owned();
+                    .on_command(SET_LAST_UPDATE_CHECK, |_, time, data| {
+                        data.last_update_check = *time;
                     })
-                    .on_command(REQUEST_RESTART, |ctx, _, data| {
-                        if !data.auto_restart | data.disabled | data.ip.is_empty() {
+                    .on_command(REQUEST_RESTART, |ctx, (id, generation), data| {
+                        if !data.auto_restart {
                             return;
                         }
-                        std::thread::sleep(std::time::Duration::from_secs(5));
-                        if !data.disabled {
-                            let token = data.token.to_owned();
+                        // 退出的 HiPer 已经回到未启动，之后用户是否手动启动或停止过由守护程序的代数判断
+                        let options = if *id == PRIMARY_INSTANCE {
+                            if data.disabled {
+                                return;
+                            }
+                            HiperLaunchOptions::from(&*data)
+                        } else {
+                            match data.network(*id) {
+                                Some(network) if !network.disabled => {
+                                    HiperLaunchOptions::for_network(data, network)
+                                }
+                                _ => {
+                                    return;
+                                }
+                            }
+                        };
+                        supervisor::schedule_restart(ctx.get_external_handle(), *id, options, *generation);
+                    })
+                    .on_command(TOGGLE_NETWORK, |ctx, id, data| {
+                        if let Some(network) = data.network(*id) {
                             let ctx = ctx.get_external_handle();
-                            run_hiper_in_thread(
-                                ctx,
-                                token,
-                                data.use_tun,
-                                data.use_tcp,
-                                data.use_igmp,
-                                data.fast_mode,
-                                data.debug_mode,
-                                data.kill_hiper_when_start
-                            );
+                            match network.connection.stage {
+                                LaunchStage::Idle => {
+                                    hiper::start_hiper(
+                                        ctx,
+                                        *id,
+                                        HiperLaunchOptions::for_network(data, network),
+                                        RestartPolicy::from(&*data)
+                                    );
+                                }
+                                LaunchStage::Running => {
+                                    hiper::stop_hiper_in_thread(ctx, *id);
+                                }
+                                LaunchStage::Stopping => {}
+                                _ => {
+                                    instance::get(*id).cancel_launch();
+                                }
+                            }
                         }
                     })
+                    .on_command(REMOVE_NETWORK, |_, id, data| {
+                        data.networks.retain(|x| x.id != *id);
+                        instance::remove(*id);
+                    })
+                    .on_command(SET_HIPER_VERSION, |_, version, data| {
+                        data.hiper_version = version.to_owned();
+                    })
+                    .on_command(SET_AVAILABLE_HIPER, |_, (version, changelog), data| {
+                        data.available_hiper_version = version.to_owned();
+                        data.available_hiper_changelog = changelog.to_owned();
+                    })
+                    .on_command(RESTART_BRIDGE, move |ctx, _, data| {
+                        println!("Restarting to the updated HiPer Bridge");
+                        let mut saved_app_state = saved_app_state_r.lock().unwrap();
+                        *saved_app_state = data.to_owned();
+                        save_config(&saved_app_state);
+                        self_update::request_restart();
+                        ctx.submit_command(CLOSE_ALL_WINDOWS);
+                        ctx.submit_command(QUIT_APP);
+                    })
                     .on_notify(BACK_PAGE_CLICKED, |ctx, _, _| {
                         ctx.submit_command(QUERY_POP_PAGE.with("main"));
                         ctx.submit_command(ENABLE_BACK_PAGE.with(false));
@@ -107,7 +239,6 @@ fn main() {
                             ctx.submit_command(QUIT_APP);
                         }
                     })
-                    .disabled_if(|data, _| data.disabled)
             )
                 .set_position({
                     let monitors = Screen::get_monitors();
@@ -193,18 +324,37 @@ fn main() {
             env.set(druid::theme::TEXTBOX_BORDER_RADIUS, 2.0);
         });
 
+        if let Some(hiper_path) = installed_hiper_path.take() {
+            let ctx = app.get_external_handle();
+            std::thread::spawn(move || hiper_version::report_installed_version(&ctx, &hiper_path));
+        }
+        if let Some((mirrors, channel)) = bridge_update.take() {
+            self_update::update_in_thread(app.get_external_handle(), mirrors, channel);
+        }
+
         app.launch(cloned_app_state).unwrap();
 
-        if !hiper::is_running() {
+        if !instance::is_any_running() || self_update::is_restart_requested() {
             break;
         }
 
         let t = Instant::now();
 
         // 恢复窗口关闭期间的运行时间
-        saved_app_state.lock().unwrap().run_time += t.elapsed().as_secs() as usize;
+        let elapsed = t.elapsed().as_secs() as usize;
+        let mut state = saved_app_state.lock().unwrap();
+        state.run_time += elapsed;
+        for network in state.networks.iter_mut() {
+            network.run_time += elapsed;
+        }
     }
-    hiper::stop_hiper_directly();
+    instance::stop_all();
 
     plugin::dispatch_event_and_wait("hb-exit");
+
+    if self_update::is_restart_requested() {
+        if let Err(err) = self_update::restart() {
+            crate::log_buffer::warn(format!("{:?}", err));
+        }
+    }
 }
@@ -0,0 +1,429 @@
+//! HiPer 相关文件的下载镜像
+//!
+//! 所有需要下载的文件（`packages.sha1`、HiPer 程序、WinTUN 和 WinTAP 安装程序）都通过
+//! [`MirrorList`] 按顺序尝试各个镜像下载，上一次可用的镜像会被优先尝试
+
+use std::{ path::Path, sync::Arc };
+
+use druid::{ Data, ExtEventSink, Target };
+use tinyjson::JsonValue;
+
+use crate::{
+    download::DownloadProgress,
+    http::{ self, HttpClient },
+    stage::LaunchCancelled,
+    ui::SET_LAST_MIRROR,
+    DynResult,
+};
+
+/// 默认的下载镜像
+pub const DEFAULT_MIRROR_URL: &str = "https://gitcode.net/qq_43650370/hiper/-/raw/master";
+
+/// 设置后会代替配置中的镜像作为唯一的下载地址，可以是 `file://` 开头的本地目录，用于在本地检查下载和更新流程
+pub const BASE_URL_ENV: &str = "HIPER_BRIDGE_BASE_URL";
+
+/// 架构地址模板中会被替换为系统架构的占位符
+pub const ARCH_PLACEHOLDER: &str = "{arch}";
+
+/// 架构地址模板中会被替换为更新渠道目录的占位符，稳定版时为空，其它渠道为 `<目录>/`
+pub const CHANNEL_PLACEHOLDER: &str = "{channel}";
+
+#[derive(Debug, Clone, PartialEq, Eq, Data)]
+pub struct Mirror {
+    /// 镜像的根地址，`packages.sha1` 和 WinTAP 安装程序位于此处
+    pub url: String,
+    /// 架构相关文件所在地址的模板，其中的 `{arch}` 会被替换为系统架构，为空时使用 `<url>/{arch}`
+    ///
+    /// 需要使用测试版等其它更新渠道时，模板中还应包含 `{channel}`，例如 `https://example.com/{channel}{arch}`
+    pub arch_url: String,
+}
+
+impl Mirror {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            arch_url: "".into(),
+        }
+    }
+
+    /// 获取镜像中某个目录的视图，用于下载其它更新渠道或固定版本的文件，`dir` 为空时即为镜像本身
+    pub fn in_dir(&self, dir: &str) -> Self {
+        let dir = dir.trim_matches('/');
+        if dir.is_empty() {
+            return Self {
+                url: self.url.to_owned(),
+                arch_url: self.arch_url.replace(CHANNEL_PLACEHOLDER, ""),
+            };
+        }
+        Self {
+            url: format!("{}/{}", self.url.trim().trim_end_matches('/'), dir),
+            arch_url: self.arch_url.replace(CHANNEL_PLACEHOLDER, &format!("{}/", dir)),
+        }
+    }
+
+    /// 镜像根目录下的文件地址
+    pub fn file_url(&self, name: &str) -> String {
+        format!("{}/{}", self.url.trim().trim_end_matches('/'), name)
+    }
+
+    /// 指定架构目录下的文件地址
+    pub fn arch_file_url(&self, arch: &str, name: &str) -> String {
+        let template = self.arch_url.trim();
+        let base = if template.is_empty() {
+            format!("{}/{}", self.url.trim().trim_end_matches('/'), arch)
+        } else {
+            template.replace(ARCH_PLACEHOLDER, arch)
+        };
+        format!("{}/{}", base.trim_end_matches('/'), name)
+    }
+
+    /// 从配置文件中读取镜像，可以是单独的根地址字符串，也可以是包含 `url` 和 `arch_url` 的对象
+    pub fn from_json(value: &JsonValue) -> Option<Self> {
+        match value {
+            JsonValue::String(url) => Some(Self::new(url.to_owned())),
+            JsonValue::Object(data) => {
+                let url = data.get("url")?.get::<String>()?.to_owned();
+                let arch_url = data
+                    .get("arch_url")
+                    .and_then(|x| x.get::<String>())
+                    .cloned()
+                    .unwrap_or_default();
+                Some(Self { url, arch_url })
+            }
+            _ => None,
+        }
+        .filter(|x| !x.url.trim().is_empty())
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut data = std::collections::HashMap::with_capacity(2);
+        data.insert("url".into(), JsonValue::String(self.url.to_owned()));
+        data.insert("arch_url".into(), JsonValue::String(self.arch_url.to_owned()));
+        JsonValue::Object(data)
+    }
+}
+
+pub fn default_mirrors() -> Vec<Mirror> {
+    vec![Mirror::new(DEFAULT_MIRROR_URL)]
+}
+
+/// 按顺序尝试的镜像列表
+#[derive(Clone)]
+pub struct MirrorList {
+    mirrors: Vec<Mirror>,
+    /// 上一次下载成功的镜像根地址
+    last_working: Option<String>,
+    /// 发出请求的客户端，为 `None` 时按地址选择，见 [`http::client_for`]
+    client: Option<Arc<dyn HttpClient>>,
+}
+
+impl std::fmt::Debug for MirrorList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MirrorList")
+            .field("mirrors", &self.mirrors)
+            .field("last_working", &self.last_working)
+            .field("client", &self.client.as_ref().map(|_| "..."))
+            .finish()
+    }
+}
+
+impl PartialEq for MirrorList {
+    fn eq(&self, other: &Self) -> bool {
+        let same_client = match (&self.client, &other.client) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        };
+        self.mirrors == other.mirrors && self.last_working == other.last_working && same_client
+    }
+}
+
+impl Eq for MirrorList {}
+
+impl Default for MirrorList {
+    fn default() -> Self {
+        Self::new(default_mirrors(), None)
+    }
+}
+
+impl MirrorList {
+    pub fn new(mirrors: Vec<Mirror>, last_working: Option<String>) -> Self {
+        let base_url = std::env::var(BASE_URL_ENV).unwrap_or_default();
+        let mirrors = if !base_url.trim().is_empty() {
+            vec![Mirror::new(base_url.trim())]
+        } else if mirrors.is_empty() {
+            default_mirrors()
+        } else {
+            mirrors
+        };
+        Self {
+            mirrors,
+            last_working: last_working.filter(|x| !x.is_empty()),
+            client: None,
+        }
+    }
+
+    /// 使用指定的客户端发出所有请求，而不是按地址选择
+    pub fn with_client(mut self, client: Arc<dyn HttpClient>) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    fn client_for(&self, url: &str) -> Arc<dyn HttpClient> {
+        self.client.clone().unwrap_or_else(|| http::client_for(url))
+    }
+
+    /// 发送 GET 请求，服务器返回的状态码不是 2xx 时视为失败
+    fn get_ok(&self, url: &str) -> DynResult<http::Response> {
+        let res = self.client_for(url).get(url)?;
+        if !(200..300).contains(&res.status_code) {
+            anyhow::bail!("HTTP {}", res.status_code);
+        }
+        Ok(res)
+    }
+
+    /// 实际尝试的顺序，上一次可用的镜像排在最前，其余保持配置中的顺序
+    pub fn ordered(&self) -> Vec<&Mirror> {
+        let mut mirrors: Vec<&Mirror> = self.mirrors.iter().collect();
+        if let Some(last_working) = &self.last_working {
+            if let Some(index) = mirrors.iter().position(|x| &x.url == last_working) {
+                let mirror = mirrors.remove(index);
+                mirrors.insert(0, mirror);
+            }
+        }
+        mirrors
+    }
+
+    /// 依次对各个镜像执行 `attempt`，返回第一个成功的镜像根地址和结果
+    ///
+    /// 用户取消启动时不会再尝试其余的镜像
+    fn try_mirrors<T>(&self, mut attempt: impl FnMut(&Mirror) -> DynResult<T>) -> DynResult<(String, T)> {
+        let mut last_error = None;
+        for mirror in self.ordered() {
+            match attempt(mirror) {
+                Ok(result) => {
+                    return Ok((mirror.url.to_owned(), result));
+                }
+                Err(err) if err.is::<LaunchCancelled>() => {
+                    return Err(err);
+                }
+                Err(err) => {
+                    crate::log_buffer::warn(format!("镜像 {} 下载失败：{:?}", mirror.url, err));
+                    last_error = Some(err);
+                }
+            }
+        }
+        Err(
+            last_error
+                .unwrap_or_else(|| anyhow::anyhow!("没有可用的下载镜像"))
+                .context("所有下载镜像均不可用")
+        )
+    }
+
+    /// 记录可用的镜像，下次优先尝试，并通知界面保存到配置中
+    fn set_working(&mut self, ctx: &ExtEventSink, url: String) {
+        if self.last_working.as_ref() != Some(&url) {
+            let _ = ctx.submit_command(SET_LAST_MIRROR, url.to_owned(), Target::Auto);
+            self.last_working = Some(url);
+        }
+    }
+
+    /// 依次尝试各个镜像下载 `url_of` 生成的地址，返回第一个成功的响应，用于下载索引等较小的文件
+    ///
+    /// 只应用于镜像必须提供的文件，成功的镜像会被记录为下次优先尝试的镜像
+    pub fn fetch(
+        &mut self,
+        ctx: &ExtEventSink,
+        url_of: impl Fn(&Mirror) -> String
+    ) -> DynResult<http::Response> {
+        let (url, res) = self.try_mirrors(|mirror| self.get_ok(&url_of(mirror)))?;
+        self.set_working(ctx, url);
+        Ok(res)
+    }
+
+    /// 与 [`MirrorList::fetch`] 相同，用于 `packages.sha256`、更新日志等镜像可以不提供的文件
+    ///
+    /// 缺少这些文件不代表镜像不可用，因此不会改变下次优先尝试的镜像
+    pub fn fetch_optional(&self, url_of: impl Fn(&Mirror) -> String) -> DynResult<http::Response> {
+        self.try_mirrors(|mirror| self.get_ok(&url_of(mirror))).map(|x| x.1)
+    }
+
+    /// 依次尝试各个镜像，将 `url_of` 生成的地址流式下载到 `part_path` 并使用 `verify` 校验，
+    /// 见 [`HttpClient::download`]
+    ///
+    /// 校验失败视为该镜像下载失败：`part_path` 会被删除，避免下一个镜像在损坏的文件后继续下载
+    pub fn download_to(
+        &mut self,
+        ctx: &ExtEventSink,
+        url_of: impl Fn(&Mirror) -> String,
+        part_path: &Path,
+        on_progress: &mut dyn FnMut(&DownloadProgress) -> DynResult,
+        verify: impl Fn(&Path) -> DynResult
+    ) -> DynResult {
+        let url = self.download_from_any(url_of, part_path, on_progress, verify)?;
+        self.set_working(ctx, url);
+        Ok(())
+    }
+
+    /// 下载并校验，返回成功的镜像根地址，见 [`MirrorList::download_to`]
+    fn download_from_any(
+        &self,
+        url_of: impl Fn(&Mirror) -> String,
+        part_path: &Path,
+        on_progress: &mut dyn FnMut(&DownloadProgress) -> DynResult,
+        verify: impl Fn(&Path) -> DynResult
+    ) -> DynResult<String> {
+        let (url, _) = self.try_mirrors(|mirror| {
+            let url = url_of(mirror);
+            crate::log_buffer::info(format!("正在下载 {}", url));
+            self.client_for(&url).download(&url, part_path, on_progress)?;
+            if let Err(err) = verify(part_path) {
+                let _ = std::fs::remove_file(part_path);
+                return Err(err);
+            }
+            crate::log_buffer::info(format!("已下载并校验 {}", url));
+            Ok(())
+        })?;
+        Ok(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{ http::{ ProxyConfig, UreqClient }, integrity::{ sha1_hex, PackageIndex }, test_server::TestServer };
+
+    const BODY: &[u8] = b"hiper binary";
+
+    fn mirrors(urls: &[String], last_working: Option<&str>) -> MirrorList {
+        let client = UreqClient::with_proxy(ProxyConfig {
+            url: "".into(),
+            no_proxy: "*".into(),
+        });
+        MirrorList {
+            mirrors: urls
+                .iter()
+                .map(|x| Mirror::new(x.to_owned()))
+                .collect(),
+            last_working: last_working.map(str::to_owned),
+            client: None,
+        }.with_client(Arc::new(client))
+    }
+
+    fn part_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("hiper-bridge-mirror-test-{}-{}.download", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn index() -> PackageIndex {
+        PackageIndex::parse(&format!("{}  linux-amd64/hiper\n", sha1_hex(BODY)), None)
+    }
+
+    #[test]
+    fn arch_urls_follow_templates() {
+        let mirror = Mirror {
+            url: "https://example.com/hiper/".into(),
+            arch_url: "https://cdn.example.com/{channel}{arch}".into(),
+        };
+        assert_eq!(mirror.file_url("packages.sha1"), "https://example.com/hiper/packages.sha1");
+        assert_eq!(mirror.in_dir("").arch_file_url("linux-amd64", "hiper"), "https://cdn.example.com/linux-amd64/hiper");
+        assert_eq!(
+            mirror.in_dir("beta").arch_file_url("linux-amd64", "hiper"),
+            "https://cdn.example.com/beta/linux-amd64/hiper"
+        );
+        assert_eq!(Mirror::new("https://example.com").arch_file_url("linux-386", "hiper"), "https://example.com/linux-386/hiper");
+    }
+
+    #[test]
+    fn last_working_mirror_is_tried_first() {
+        let list = mirrors(&["https://a".into(), "https://b".into(), "https://c".into()], Some("https://b"));
+        let order: Vec<&str> = list
+            .ordered()
+            .iter()
+            .map(|x| x.url.as_str())
+            .collect();
+        assert_eq!(order, ["https://b", "https://a", "https://c"]);
+    }
+
+    #[test]
+    fn fetch_falls_back_to_next_mirror() {
+        let broken = TestServer::start(&[]);
+        let working = TestServer::start(&[("/packages.sha1", b"index")]);
+        let list = mirrors(&[broken.url(), working.url()], None);
+        let res = list.fetch_optional(|mirror| mirror.file_url("packages.sha1")).unwrap();
+        assert_eq!(res.as_str().unwrap(), "index");
+        assert_eq!(broken.requests().len(), 1);
+        assert_eq!(list.last_working, None);
+    }
+
+    #[test]
+    fn fetch_fails_when_every_mirror_fails() {
+        let a = TestServer::start(&[]);
+        let b = TestServer::start(&[]);
+        let list = mirrors(&[a.url(), b.url()], None);
+        assert!(list.fetch_optional(|mirror| mirror.file_url("packages.sha1")).is_err());
+        assert_eq!(a.requests().len(), 1);
+        assert_eq!(b.requests().len(), 1);
+    }
+
+    #[test]
+    fn download_falls_back_to_next_mirror() {
+        let broken = TestServer::start(&[]);
+        let working = TestServer::start(&[("/linux-amd64/hiper", BODY)]);
+        let list = mirrors(&[broken.url(), working.url()], None);
+        let part = part_path("fallback");
+        let index = index();
+        let url = list
+            .download_from_any(
+                |mirror| mirror.arch_file_url("linux-amd64", "hiper"),
+                &part,
+                &mut |_| Ok(()),
+                |part| index.verify_file("linux-amd64/hiper", part)
+            )
+            .unwrap();
+        assert_eq!(url, working.url());
+        assert_eq!(std::fs::read(&part).unwrap(), BODY);
+        let _ = std::fs::remove_file(part);
+    }
+
+    #[test]
+    fn checksum_mismatch_deletes_part_file_and_tries_next_mirror() {
+        let corrupt = TestServer::start(&[("/linux-amd64/hiper", b"hiper binarx")]);
+        let working = TestServer::start(&[("/linux-amd64/hiper", BODY)]);
+        let list = mirrors(&[corrupt.url(), working.url()], None);
+        let part = part_path("mismatch");
+        let index = index();
+        let url = list
+            .download_from_any(
+                |mirror| mirror.arch_file_url("linux-amd64", "hiper"),
+                &part,
+                &mut |_| Ok(()),
+                |part| index.verify_file("linux-amd64/hiper", part)
+            )
+            .unwrap();
+        assert_eq!(url, working.url());
+        assert_eq!(std::fs::read(&part).unwrap(), BODY);
+        // 损坏的文件被删除后，下一个镜像应当从头下载，而不是在损坏的文件后继续
+        assert_eq!(working.requests()[0].range, None);
+        let _ = std::fs::remove_file(part);
+    }
+
+    #[test]
+    fn checksum_mismatch_on_every_mirror_leaves_no_part_file() {
+        let corrupt = TestServer::start(&[("/linux-amd64/hiper", b"hiper binarx")]);
+        let list = mirrors(&[corrupt.url()], None);
+        let part = part_path("all-corrupt");
+        let index = index();
+        let result = list.download_from_any(
+            |mirror| mirror.arch_file_url("linux-amd64", "hiper"),
+            &part,
+            &mut |_| Ok(()),
+            |part| index.verify_file("linux-amd64/hiper", part)
+        );
+        assert!(result.is_err());
+        assert!(!part.exists());
+    }
+}
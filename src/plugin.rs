@@ -2,6 +2,7 @@ use std::{
     io::{Cursor, Read},
     path::{Path, PathBuf},
     process::Child,
+    sync::Arc,
 };
 
 use anyhow::Context;
@@ -11,7 +12,11 @@ use tinyjson::*;
 
 use crate::{
     hiper::get_hiper_dir,
-    ui::{SET_START_TEXT, SET_WARNING},
+    http::{self, HttpClient, Response},
+    instance::HiperInstance,
+    integrity::sha256_hex,
+    stage::{ConnectionState, LaunchStage},
+    ui::SET_WARNING,
     utils::write_file_safe,
     DynResult,
 };
@@ -39,9 +44,14 @@ impl TinyJsonGet for JsonValue {
 }
 
 pub fn dispatch_event(event_name: &str) -> Vec<Child> {
+    dispatch_event_with_env(event_name, &[])
+}
+
+/// 触发事件，并将事件参数以环境变量的方式传递给插件脚本
+pub fn dispatch_event_with_env(event_name: &str, env: &[(&str, String)]) -> Vec<Child> {
     load_plugins()
         .into_iter()
-        .flat_map(|x| x.dispatch_event(event_name))
+        .flat_map(|x| x.dispatch_event(event_name, env))
         .collect()
 }
 
@@ -50,15 +60,15 @@ pub fn dispatch_event_and_wait(event_name: &str) {
         match child.wait() {
             Ok(status) => {
                 if !status.success() {
-                    println!(
-                        "[WARN] 有插件触发 {} 事件执行失败，返回值：{}",
+                    crate::log_buffer::warn(format!(
+                        "有插件触发 {} 事件执行失败，返回值：{}",
                         event_name,
                         status.code().unwrap_or_default()
-                    );
+                    ));
                 }
             }
             Err(err) => {
-                println!("[WARN] 有插件触发 {} 事件执行出错：{}", event_name, err);
+                crate::log_buffer::warn(format!("有插件触发 {} 事件执行出错：{}", event_name, err));
             }
         }
     }
@@ -66,9 +76,15 @@ pub fn dispatch_event_and_wait(event_name: &str) {
 
 /// 读取当前已有的所有插件
 pub fn load_plugins() -> Vec<Plugin> {
+    load_plugins_with_errors().0
+}
+
+/// 读取当前已有的所有插件，同时返回无法加载的插件的错误信息
+pub fn load_plugins_with_errors() -> (Vec<Plugin>, Vec<String>) {
+    let mut plugins = Vec::with_capacity(16);
+    let mut errors = vec![];
     if let Ok(hiper_dir) = get_hiper_dir() {
         if let Ok(mut read_dir) = std::fs::read_dir(hiper_dir.join("plugins")) {
-            let mut plugins = Vec::with_capacity(16);
             while let Some(Ok(entry)) = read_dir.next() {
                 let plugin_json_path = entry.path().join("plugin.json");
                 if plugin_json_path.is_file() {
@@ -77,95 +93,157 @@ pub fn load_plugins() -> Vec<Plugin> {
                             plugins.push(plugin_json);
                         }
                         Err(err) => {
-                            println!(
-                                "[WARN] 无法加载插件 {} ：{}",
+                            let error = format!(
+                                "无法加载插件 {} ：{}",
                                 entry.path().to_string_lossy(),
                                 err
                             );
+                            crate::log_buffer::warn(error.as_str());
+                            errors.push(error);
                         }
                     }
                 }
             }
-            return plugins;
         }
     }
-    vec![]
+    (plugins, errors)
 }
 
-pub fn update_plugins(ctx: ExtEventSink) {
-    let _ = ctx.submit_command(SET_START_TEXT, "正在检查插件更新", Target::Auto);
-    let _ = ctx.submit_command(SET_WARNING, "".to_string(), Target::Auto);
+/// 检查并安装所有插件的更新，单个插件更新失败不会影响其它插件和 HiPer 的启动
+pub fn update_plugins(ctx: ExtEventSink, instance: &HiperInstance) {
+    instance.set_stage(&ctx, ConnectionState::new(LaunchStage::CheckingPlugins));
+    let _ = ctx.submit_command(SET_WARNING, (instance.id(), "".to_string()), Target::Auto);
 
+    let updater = PluginUpdater::default();
     for plugin in load_plugins() {
+        let result = updater.update(&plugin, &mut || {
+            instance.set_stage(
+                &ctx,
+                ConnectionState::with_detail(LaunchStage::UpdatingPlugins, &plugin.name),
+            );
+        });
+        match result {
+            Ok(true) => crate::log_buffer::info(format!("已更新插件 {}", plugin.name)),
+            Ok(false) => {}
+            Err(err) => crate::log_buffer::warn(format!("无法更新插件 {}：{:?}", plugin.name, err)),
+        }
+    }
+}
+
+/// 插件更新的检查、下载和解压
+#[derive(Clone, Default)]
+pub struct PluginUpdater {
+    /// 发出请求的客户端，为 `None` 时按地址选择，见 [`http::client_for`]
+    client: Option<Arc<dyn HttpClient>>,
+}
+
+impl PluginUpdater {
+    /// 使用指定的客户端发出所有请求，而不是按地址选择
+    pub fn with_client(client: Arc<dyn HttpClient>) -> Self {
+        Self {
+            client: Some(client),
+        }
+    }
+
+    /// 发送 GET 请求，服务器返回的状态码不是 200 时视为失败
+    fn get_ok(&self, url: &str) -> DynResult<Response> {
+        let client = self.client.clone().unwrap_or_else(|| http::client_for(url));
+        let res = client.get(url)?;
+        if res.status_code != 200 {
+            anyhow::bail!("HTTP {}", res.status_code);
+        }
+        Ok(res)
+    }
+
+    /// 检查并安装插件的更新，`on_update` 会在开始下载更新包前调用
+    ///
+    /// 插件没有更新地址、已是最新版本或没有适用于当前系统的更新包时返回 `Ok(false)`。
+    /// 更新包的 SHA-256 摘要不一致或无法解压时不会写入任何文件
+    pub fn update(&self, plugin: &Plugin, on_update: &mut dyn FnMut()) -> DynResult<bool> {
         if plugin.update_url.is_empty() {
-            continue;
+            return Ok(false);
+        }
+        let res = self
+            .get_ok(&plugin.update_url)
+            .context("无法获取插件更新元数据")?;
+        let update_meta = PluginUpdateMeta::from_str(res.as_str()?)?;
+        if update_meta.version == plugin.version {
+            return Ok(false);
         }
-        if let Ok(res) = tinyget::get(&plugin.update_url).send() {
-            if res.status_code != 200 {
-                continue;
+        let target_download = match update_meta.downloads.iter().find(|x| x.is_downloadable()) {
+            Some(target_download) => target_download,
+            None => {
+                return Ok(false);
             }
-            if let Ok(Ok(update_meta)) = res.as_str().map(PluginUpdateMeta::from_str) {
-                if update_meta.version == plugin.version {
-                    continue;
-                }
-                if let Some(target_download) =
-                update_meta.downloads.iter().find(|x| x.is_downloadable())
-            {
-                let _ = ctx.submit_command(
-                    SET_START_TEXT,
-                    "正在更新插件",
-                    Target::Auto,
+        };
+        on_update();
+        let url = resolve_url(&plugin.update_url, &target_download.url);
+        let res = self.get_ok(&url).context("无法下载插件更新包")?;
+        if !target_download.sha256.is_empty() {
+            let actual = sha256_hex(res.as_bytes());
+            if actual != target_download.sha256.to_lowercase() {
+                anyhow::bail!(
+                    "插件更新包的 SHA-256 摘要不一致，文件可能已损坏或被篡改（应为 {}，实际为 {}）",
+                    target_download.sha256,
+                    actual
                 );
-                let mut buf = Vec::with_capacity(4096);
-                if let Ok(res) = tinyget::get(&target_download.url).send() {
-                    if res.status_code != 200 {
-                        continue;
-                    }
-                    let r = Cursor::new(res.as_bytes());
-                    if let Ok(mut z) = zip::ZipArchive::new(r) {
-                        for i in 0..z.len() {
-                            if let Ok(mut e) = z.by_index(i) {
-                                if let Ok(final_path) = plugin
-                                    .path
-                                    .join(e.name())
-                                    .absolutize()
-                                    .map(PathBuf::from)
-                                {
-                                    // 确保不会恶意写入到外部
-                                    if !final_path.starts_with(&plugin.path) {
-                                        continue;
-                                    }
-                                    if e.is_file() {
-                                        if let Some(parent_dir) =
-                                            final_path.parent()
-                                        {
-                                            let _ = std::fs::create_dir_all(
-                                                parent_dir,
-                                            );
-                                            if let Ok(l) =
-                                                e.read_to_end(&mut buf)
-                                            {
-                                                let _ = write_file_safe(
-                                                    final_path,
-                                                    &buf[0..l],
-                                                );
-                                                buf.clear();
-                                            }
-                                        }
-                                    } else if e.is_dir() {
-                                        let _ = std::fs::create_dir_all(
-                                            final_path,
-                                        );
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
             }
         }
+        extract_plugin(&plugin.path, res.as_bytes())?;
+        Ok(true)
+    }
+}
+
+/// 解析更新元数据中的下载地址，相对地址以更新元数据所在的目录为基准
+fn resolve_url(update_url: &str, url: &str) -> String {
+    if url.contains("://") {
+        return url.to_owned();
+    }
+    let dir = update_url
+        .rsplit_once('/')
+        .map(|x| x.0)
+        .unwrap_or(update_url);
+    format!("{}/{}", dir, url.trim_start_matches("./"))
+}
+
+/// 将插件更新包解压到插件目录，所有文件都读取成功后才会开始写入，压缩包损坏时插件保持不变
+fn extract_plugin(plugin_dir: &Path, data: &[u8]) -> DynResult {
+    let mut archive =
+        zip::ZipArchive::new(Cursor::new(data)).context("插件更新包不是有效的 zip 压缩包")?;
+    let mut dirs = vec![];
+    let mut files = Vec::with_capacity(archive.len());
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).context("无法读取插件更新包")?;
+        let final_path = match plugin_dir.join(entry.name()).absolutize() {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => continue,
+        };
+        // 确保不会恶意写入到外部
+        if !final_path.starts_with(plugin_dir) {
+            continue;
+        }
+        if entry.is_dir() {
+            dirs.push(final_path);
+        } else if entry.is_file() {
+            let mut buf = Vec::with_capacity(entry.size() as usize);
+            entry
+                .read_to_end(&mut buf)
+                .with_context(|| format!("无法解压插件更新包中的 {}", entry.name()))?;
+            files.push((final_path, buf));
+        }
+    }
+    for dir in dirs {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("无法创建文件夹 {}", dir.display()))?;
     }
+    for (path, data) in files {
+        if let Some(parent_dir) = path.parent() {
+            std::fs::create_dir_all(parent_dir)
+                .with_context(|| format!("无法创建文件夹 {}", parent_dir.display()))?;
+        }
+        write_file_safe(&path, &data).with_context(|| format!("无法写入 {}", path.display()))?;
+    }
+    Ok(())
 }
 
 pub struct Plugin {
@@ -194,6 +272,8 @@ pub struct PluginDownloadItem {
     system: String,
     arch: String,
     url: String,
+    /// 更新包的 SHA-256 摘要，为空时不校验
+    sha256: String,
 }
 
 impl Plugin {
@@ -296,11 +376,21 @@ impl Plugin {
         &self.id
     }
 
-    pub fn dispatch_event(&self, event_name: &str) -> Vec<Child> {
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn dispatch_event(&self, event_name: &str, env: &[(&str, String)]) -> Vec<Child> {
         self.scripts
             .iter()
             .filter(|x| x.on == event_name && x.should_run())
-            .map(|x| x.run_script(Some(&self.path)))
+            .map(|x| {
+                crate::log_buffer::info(format!(
+                    "运行插件 {} 的 {} 事件脚本",
+                    self.name, event_name
+                ));
+                x.run_script(Some(&self.path), env)
+            })
             .filter_map(|x| x.ok())
             .collect()
     }
@@ -369,7 +459,7 @@ impl PluginScript {
         system && arch
     }
 
-    pub fn run_script(&self, cwd: Option<&Path>) -> DynResult<Child> {
+    pub fn run_script(&self, cwd: Option<&Path>, env: &[(&str, String)]) -> DynResult<Child> {
         let mut p = std::process::Command::new({
             #[cfg(target_os = "windows")]
             {
@@ -385,6 +475,7 @@ impl PluginScript {
             }
         });
         p.stdin(std::process::Stdio::piped());
+        p.envs(env.iter().map(|(k, v)| (*k, v)));
         if let Some(cwd) = cwd {
             if cwd.is_dir() {
                 p.current_dir(cwd);
@@ -460,7 +551,16 @@ impl PluginDownloadItem {
             .try_get_into::<String>("arch")
             .cloned()
             .unwrap_or_default();
-        Ok(Self { url, system, arch })
+        let sha256 = value
+            .try_get_into::<String>("sha256")
+            .cloned()
+            .unwrap_or_default();
+        Ok(Self {
+            url,
+            system,
+            arch,
+            sha256,
+        })
     }
 
     pub fn is_downloadable(&self) -> bool {
@@ -488,3 +588,198 @@ impl PluginDownloadItem {
         system && arch
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::{write::FileOptions, ZipWriter};
+
+    use super::*;
+    use crate::{
+        http::{ProxyConfig, UreqClient},
+        test_server::TestServer,
+    };
+
+    fn updater() -> PluginUpdater {
+        PluginUpdater::with_client(Arc::new(UreqClient::with_proxy(ProxyConfig {
+            url: "".into(),
+            no_proxy: "*".into(),
+        })))
+    }
+
+    fn plugin_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "hiper-bridge-plugin-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("plugin")).unwrap();
+        dir
+    }
+
+    fn plugin(dir: &Path, version: &str, update_url: &str) -> Plugin {
+        let mut plugin = Plugin::from_str(&format!(
+            r#"{{"_version":1,"id":"test","plugin_version":"{}","update_url":"{}"}}"#,
+            version, update_url
+        ))
+        .unwrap();
+        plugin.path = dir.join("plugin");
+        plugin
+    }
+
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        for (name, data) in entries {
+            if name.ends_with('/') {
+                zip.add_directory(*name, FileOptions::default()).unwrap();
+            } else {
+                zip.start_file(*name, FileOptions::default()).unwrap();
+                zip.write_all(data).unwrap();
+            }
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn update_meta(version: &str, url: &str, sha256: &str) -> Vec<u8> {
+        format!(
+            r#"{{"version":"{}","downloads":[{{"url":"{}","sha256":"{}"}}]}}"#,
+            version, url, sha256
+        )
+        .into_bytes()
+    }
+
+    fn targets(server: &TestServer) -> Vec<String> {
+        server.requests().into_iter().map(|x| x.target).collect()
+    }
+
+    #[test]
+    fn update_downloads_and_extracts_zip() {
+        let dir = plugin_dir("extract");
+        let archive = zip(&[
+            ("plugin.json", b"{}"),
+            ("scripts/", b""),
+            ("scripts/run.sh", b"echo hi"),
+            ("../outside.txt", b"evil"),
+        ]);
+        let meta = update_meta("1.1.0", "plugin.zip", &sha256_hex(&archive));
+        let server = TestServer::start(&[("/update.json", &meta), ("/plugin.zip", &archive)]);
+        let plugin = plugin(&dir, "1.0.0", &format!("{}/update.json", server.url()));
+
+        let mut notified = false;
+        assert!(updater().update(&plugin, &mut || notified = true).unwrap());
+
+        assert!(notified);
+        assert_eq!(targets(&server), ["/update.json", "/plugin.zip"]);
+        assert_eq!(
+            std::fs::read(dir.join("plugin/plugin.json")).unwrap(),
+            b"{}"
+        );
+        assert_eq!(
+            std::fs::read(dir.join("plugin/scripts/run.sh")).unwrap(),
+            b"echo hi"
+        );
+        assert!(!dir.join("outside.txt").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn update_skips_when_version_matches() {
+        let dir = plugin_dir("skip");
+        let archive = zip(&[("plugin.json", b"{}")]);
+        let meta = update_meta("1.0.0", "plugin.zip", &sha256_hex(&archive));
+        let server = TestServer::start(&[("/update.json", &meta), ("/plugin.zip", &archive)]);
+        let plugin = plugin(&dir, "1.0.0", &format!("{}/update.json", server.url()));
+
+        assert!(!updater()
+            .update(&plugin, &mut || panic!("不应下载"))
+            .unwrap());
+
+        assert_eq!(targets(&server), ["/update.json"]);
+        assert!(!dir.join("plugin/plugin.json").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn update_rejects_hash_mismatch() {
+        let dir = plugin_dir("hash");
+        std::fs::write(dir.join("plugin/plugin.json"), b"old").unwrap();
+        let archive = zip(&[("plugin.json", b"tampered")]);
+        let meta = update_meta("1.1.0", "plugin.zip", &sha256_hex(b"original"));
+        let server = TestServer::start(&[("/update.json", &meta), ("/plugin.zip", &archive)]);
+        let plugin = plugin(&dir, "1.0.0", &format!("{}/update.json", server.url()));
+
+        let err = updater().update(&plugin, &mut || {}).unwrap_err();
+
+        assert!(format!("{:?}", err).contains("SHA-256"));
+        assert_eq!(
+            std::fs::read(dir.join("plugin/plugin.json")).unwrap(),
+            b"old"
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn update_rejects_corrupt_archive() {
+        let dir = plugin_dir("corrupt");
+        std::fs::write(dir.join("plugin/plugin.json"), b"old").unwrap();
+        let mut archive = zip(&[("plugin.json", b"new"), ("data.bin", &[7; 256])]);
+        // 破坏第二个文件的数据，压缩包本身和第一个文件仍然可以正常读取
+        let name = archive.windows(8).position(|x| x == b"data.bin").unwrap();
+        archive[name + 8 + 1] ^= 0xff;
+        assert!(zip::ZipArchive::new(Cursor::new(&archive)).is_ok());
+        let server = TestServer::start(&[
+            ("/update.json", &update_meta("1.1.0", "plugin.zip", "")),
+            ("/plugin.zip", &archive),
+        ]);
+        let plugin = plugin(&dir, "1.0.0", &format!("{}/update.json", server.url()));
+
+        assert!(updater().update(&plugin, &mut || {}).is_err());
+
+        assert_eq!(
+            std::fs::read(dir.join("plugin/plugin.json")).unwrap(),
+            b"old"
+        );
+        assert!(!dir.join("plugin/data.bin").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn update_reports_missing_archive() {
+        let dir = plugin_dir("missing");
+        let server =
+            TestServer::start(&[("/update.json", &update_meta("1.1.0", "plugin.zip", ""))]);
+        let plugin = plugin(&dir, "1.0.0", &format!("{}/update.json", server.url()));
+
+        let err = updater().update(&plugin, &mut || {}).unwrap_err();
+
+        assert!(format!("{:?}", err).contains("404"));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn resolve_url_handles_relative_paths() {
+        let cases = [
+            (
+                "http://a/p/update.json",
+                "plugin.zip",
+                "http://a/p/plugin.zip",
+            ),
+            (
+                "http://a/p/update.json",
+                "./v2/plugin.zip",
+                "http://a/p/v2/plugin.zip",
+            ),
+            (
+                "http://a/p/update.json",
+                "https://b/x.zip",
+                "https://b/x.zip",
+            ),
+            ("file:///tmp/update.json", "x.zip", "file:///tmp/x.zip"),
+        ];
+        for (update_url, url, expected) in cases {
+            assert_eq!(resolve_url(update_url, url), expected);
+        }
+    }
+}
@@ -0,0 +1,511 @@
+//! HiPer 进程的管理
+//!
+//! 停止进程时会先请求 HiPer 自行退出，超时后再强制结束，并且一定会回收子进程，
+//! 确保下一次启动时 TUN/TAP 设备已经被释放
+//!
+//! 启动的进程会记录在所属实例工作目录下的 `hiper.pid` 中，
+//! 以便在 HiPer Bridge 意外退出后重新找到自己启动的进程，而不会影响其它用户或服务启动的 HiPer。
+//! 其中同时记录了启动的程序路径，进程编号被其它程序重新使用时不会被误认为是 HiPer
+
+use std::{
+    path::{ Path, PathBuf },
+    process::{ Child, ExitStatus },
+    sync::{ atomic::{ AtomicBool, Ordering }, Mutex },
+    time::{ Duration, Instant },
+};
+
+use druid::Data;
+
+use crate::DynResult;
+
+/// 启动 HiPer 时对已经在运行的 HiPer 进程的处理方式
+///
+/// 无论哪种方式都不会影响并非由 HiPer Bridge 启动的 HiPer 进程
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Data)]
+pub enum ExistingProcessPolicy {
+    /// 接管上次启动且仍在运行的 HiPer，不再启动新的进程
+    Adopt,
+    /// 结束上次启动的 HiPer 后再启动新的进程
+    StopOwned,
+    /// 不处理任何已经在运行的 HiPer
+    Ignore,
+}
+
+impl ExistingProcessPolicy {
+    pub fn id(self) -> &'static str {
+        match self {
+            ExistingProcessPolicy::Adopt => "adopt",
+            ExistingProcessPolicy::StopOwned => "stop",
+            ExistingProcessPolicy::Ignore => "ignore",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<Self> {
+        match id {
+            "adopt" => Some(ExistingProcessPolicy::Adopt),
+            "stop" => Some(ExistingProcessPolicy::StopOwned),
+            "ignore" => Some(ExistingProcessPolicy::Ignore),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ExistingProcessPolicy::Adopt => "接管上次启动的 HiPer",
+            ExistingProcessPolicy::StopOwned => "结束上次启动的 HiPer",
+            ExistingProcessPolicy::Ignore => "不处理已运行的 HiPer",
+        }
+    }
+
+    /// 设置页中按顺序切换到下一个选项
+    pub fn next(self) -> Self {
+        match self {
+            ExistingProcessPolicy::Adopt => ExistingProcessPolicy::StopOwned,
+            ExistingProcessPolicy::StopOwned => ExistingProcessPolicy::Ignore,
+            ExistingProcessPolicy::Ignore => ExistingProcessPolicy::Adopt,
+        }
+    }
+}
+
+/// 由 HiPer Bridge 启动或接管的 HiPer 进程
+pub struct HiperProcess {
+    /// 接管的进程不是当前程序的子进程，无法获取其退出状态
+    child: Option<Mutex<Child>>,
+    pid: u32,
+    /// 接管的进程的程序路径，用于确认进程编号没有被其它程序重新使用
+    binary: PathBuf,
+    stop_timeout: Duration,
+    stop_requested: AtomicBool,
+}
+
+impl HiperProcess {
+    pub fn new(child: Child, stop_timeout: Duration) -> Self {
+        Self {
+            pid: child.id(),
+            child: Some(Mutex::new(child)),
+            binary: PathBuf::new(),
+            stop_timeout,
+            stop_requested: AtomicBool::new(false),
+        }
+    }
+
+    /// 接管一个已经在运行的 HiPer 进程，`binary` 为启动该进程的程序路径
+    pub fn adopt(pid: u32, binary: PathBuf, stop_timeout: Duration) -> Self {
+        Self {
+            pid,
+            child: None,
+            binary,
+            stop_timeout,
+            stop_requested: AtomicBool::new(false),
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.pid
+    }
+
+    /// 进程是否是因为 HiPer Bridge 主动停止而退出的
+    pub fn is_stop_requested(&self) -> bool {
+        self.stop_requested.load(Ordering::SeqCst)
+    }
+
+    pub fn has_exited(&self) -> bool {
+        match &self.child {
+            Some(child) =>
+                child
+                    .lock()
+                    .map(|mut x| !matches!(x.try_wait(), Ok(None)))
+                    .unwrap_or(true),
+            // 接管的进程退出后进程编号可能会被其它程序重新使用，因此还需要确认仍然是 HiPer
+            None => !is_hiper_process(self.pid, &self.binary),
+        }
+    }
+
+    /// 获取进程的退出状态，进程还在运行或是接管的进程时返回 `None`
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        self.child.as_ref()?.lock().ok()?.try_wait().ok().flatten()
+    }
+
+    /// 等待进程退出，`timeout` 为 `None` 时将一直等待，进程已退出时返回 `true`
+    ///
+    /// 等待期间不会一直占用进程锁，其它线程依然可以停止该进程
+    pub fn wait_timeout(&self, timeout: Option<Duration>) -> bool {
+        let start = Instant::now();
+        loop {
+            if self.has_exited() {
+                return true;
+            }
+            if let Some(timeout) = timeout {
+                if start.elapsed() >= timeout {
+                    return false;
+                }
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// 停止进程并回收，返回进程的退出状态
+    ///
+    /// 会先请求 HiPer 自行退出，超过停止超时后强制结束进程
+    pub fn stop(&self) -> Option<ExitStatus> {
+        self.stop_requested.store(true, Ordering::SeqCst);
+        if !self.has_exited() {
+            self.request_exit();
+            if !self.wait_timeout(Some(self.stop_timeout)) {
+                crate::log_buffer::warn(format!(
+                    "HiPer 未能在 {} 秒内退出，正在强制结束进程 {}",
+                    self.stop_timeout.as_secs(),
+                    self.pid
+                ));
+                self.kill();
+            }
+        }
+        self.exit_status()
+    }
+
+    /// 调用前需要确认进程还没有退出，见 [`HiperProcess::has_exited`]
+    fn request_exit(&self) {
+        #[cfg(unix)]
+        unsafe {
+            nix::libc::kill(self.pid as i32, nix::libc::SIGTERM);
+        }
+        // Windows 下没有可以发送给无窗口控制台程序的退出请求，只能直接结束进程
+        #[cfg(windows)]
+        self.kill();
+    }
+
+    fn kill(&self) {
+        match &self.child {
+            Some(child) => {
+                if let Ok(mut child) = child.lock() {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                }
+            }
+            None => {
+                if !self.has_exited() {
+                    kill_pid(self.pid);
+                    self.wait_timeout(Some(Duration::from_secs(5)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_pid_alive(pid: u32) -> bool {
+    if unsafe { nix::libc::kill(pid as i32, 0) } == 0 {
+        return true;
+    }
+    // 没有权限发送信号时进程也依然存在
+    std::io::Error::last_os_error().raw_os_error() == Some(nix::libc::EPERM)
+}
+
+#[cfg(windows)]
+fn is_pid_alive(pid: u32) -> bool {
+    use windows::Win32::{
+        Foundation::{ CloseHandle, STILL_ACTIVE },
+        System::Threading::{ GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION },
+    };
+    unsafe {
+        if let Ok(handle) = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid) {
+            let mut code = 0u32;
+            let alive =
+                GetExitCodeProcess(handle, &mut code).is_ok() && code == (STILL_ACTIVE.0 as u32);
+            let _ = CloseHandle(handle);
+            alive
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(unix)]
+fn kill_pid(pid: u32) {
+    unsafe {
+        nix::libc::kill(pid as i32, nix::libc::SIGKILL);
+    }
+}
+
+#[cfg(windows)]
+fn kill_pid(pid: u32) {
+    use windows::Win32::{
+        Foundation::CloseHandle,
+        System::Threading::{ OpenProcess, TerminateProcess, PROCESS_TERMINATE },
+    };
+    unsafe {
+        if let Ok(handle) = OpenProcess(PROCESS_TERMINATE, false, pid) {
+            let _ = TerminateProcess(handle, 1);
+            let _ = CloseHandle(handle);
+        }
+    }
+}
+
+/// `hiper.pid` 中记录的进程信息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PidFile {
+    pub pid: u32,
+    /// HiPer 入网后获取到的 IP 地址，还未入网时为空
+    pub ip: String,
+    /// 启动的 HiPer 程序路径，旧版本写入的文件中没有记录时为空
+    pub binary: PathBuf,
+}
+
+pub fn read_pid_file(path: &Path) -> Option<PidFile> {
+    let data = std::fs::read_to_string(path).ok()?;
+    let mut lines = data.lines();
+    let pid = lines.next()?.trim().parse().ok()?;
+    let ip = lines.next().unwrap_or_default().trim().to_owned();
+    let binary = PathBuf::from(lines.next().unwrap_or_default().trim());
+    Some(PidFile { pid, ip, binary })
+}
+
+pub fn write_pid_file(path: &Path, pid_file: &PidFile) -> DynResult {
+    crate::utils::write_file_safe(
+        path,
+        format!("{}\n{}\n{}\n", pid_file.pid, pid_file.ip, pid_file.binary.to_string_lossy()).as_bytes()
+    )?;
+    Ok(())
+}
+
+/// 删除 `hiper.pid`，如果其中记录的已经是其它进程则不做处理
+pub fn remove_pid_file(path: &Path, pid: u32) {
+    if read_pid_file(path).map(|x| x.pid == pid).unwrap_or(false) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// 查找当前系统中正在运行的所有 HiPer 进程
+#[cfg(target_os = "linux")]
+pub fn find_hiper_processes() -> Vec<u32> {
+    let mut result = Vec::with_capacity(4);
+    if let Ok(read_dir) = std::fs::read_dir("/proc") {
+        for entry in read_dir.flatten() {
+            if let Some(pid) = entry.file_name().to_str().and_then(|x| x.parse::<u32>().ok()) {
+                if has_hiper_name(pid) {
+                    result.push(pid);
+                }
+            }
+        }
+    }
+    result
+}
+
+/// 查找当前系统中正在运行的所有 HiPer 进程
+///
+/// 目前只有 Linux 可以通过 /proc 找到其它 HiPer 进程，其它平台总是返回空列表
+#[cfg(not(target_os = "linux"))]
+pub fn find_hiper_processes() -> Vec<u32> {
+    vec![]
+}
+
+/// 检查进程的名称是否是 HiPer，用于查找其它用户或服务启动的 HiPer
+#[cfg(target_os = "linux")]
+fn has_hiper_name(pid: u32) -> bool {
+    std::fs
+        ::read_to_string(format!("/proc/{}/comm", pid))
+        .map(|x| x.trim() == "hiper")
+        .unwrap_or(false)
+}
+
+/// 检查进程是否是仍在运行的 HiPer，`binary` 为启动该进程的程序路径
+///
+/// 通过进程的程序路径判断，因此使用自定义路径的 HiPer 程序也能被识别；`binary` 为空时只比较文件名。
+/// 无法获取程序路径（例如没有权限或进程已退出）时视为不是 HiPer，
+/// 以免在进程编号被其它程序重新使用后误结束或接管无关的进程
+pub fn is_hiper_process(pid: u32, binary: &Path) -> bool {
+    is_pid_alive(pid) &&
+        process_image_path(pid)
+            .map(|image| is_same_binary(&image, binary))
+            .unwrap_or(false)
+}
+
+/// 比较进程的程序路径和记录的程序路径
+fn is_same_binary(image: &Path, binary: &Path) -> bool {
+    if binary.as_os_str().is_empty() {
+        return image
+            .file_name()
+            .map(|x| x.to_string_lossy().eq_ignore_ascii_case(crate::hiper::HIPER_FILE_NAME))
+            .unwrap_or(false);
+    }
+    let image = image.canonicalize().unwrap_or_else(|_| image.to_path_buf());
+    let binary = binary.canonicalize().unwrap_or_else(|_| binary.to_path_buf());
+    if cfg!(windows) {
+        image.to_string_lossy().to_lowercase() == binary.to_string_lossy().to_lowercase()
+    } else {
+        image == binary
+    }
+}
+
+/// 获取进程的程序路径
+///
+/// 程序文件在进程运行期间被替换（例如 HiPer 被更新）时，路径后会带有 ` (deleted)`，这里会将其去除
+#[cfg(target_os = "linux")]
+fn process_image_path(pid: u32) -> Option<PathBuf> {
+    let path = std::fs::read_link(format!("/proc/{}/exe", pid)).ok()?;
+    match path.to_str().and_then(|x| x.strip_suffix(" (deleted)")) {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(path),
+    }
+}
+
+/// 获取进程的程序路径
+#[cfg(windows)]
+fn process_image_path(pid: u32) -> Option<PathBuf> {
+    use std::os::windows::ffi::OsStringExt;
+    use windows::{
+        core::PWSTR,
+        Win32::{
+            Foundation::CloseHandle,
+            System::Threading::{
+                OpenProcess,
+                QueryFullProcessImageNameW,
+                PROCESS_NAME_WIN32,
+                PROCESS_QUERY_LIMITED_INFORMATION,
+            },
+        },
+    };
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid).ok()?;
+        let mut buf = vec![0u16; 1024];
+        let mut len = buf.len() as u32;
+        let result = QueryFullProcessImageNameW(
+            handle,
+            PROCESS_NAME_WIN32,
+            PWSTR(buf.as_mut_ptr()),
+            &mut len
+        );
+        let _ = CloseHandle(handle);
+        result.ok()?;
+        Some(PathBuf::from(std::ffi::OsString::from_wide(&buf[..len as usize])))
+    }
+}
+
+/// 获取进程的程序路径
+#[cfg(target_os = "macos")]
+fn process_image_path(pid: u32) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    let mut buf = vec![0u8; nix::libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+    let len = unsafe { nix::libc::proc_pidpath(pid as i32, buf.as_mut_ptr().cast(), buf.len() as u32) };
+    if len <= 0 {
+        return None;
+    }
+    buf.truncate(len as usize);
+    Some(PathBuf::from(std::ffi::OsString::from_vec(buf)))
+}
+
+/// 将退出状态转换成便于阅读的描述
+pub fn describe_exit_status(status: &ExitStatus) -> String {
+    if let Some(code) = status.code() {
+        return format!("退出码 {}", code);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            let name = match signal {
+                nix::libc::SIGTERM => "SIGTERM",
+                nix::libc::SIGKILL => "SIGKILL",
+                nix::libc::SIGINT => "SIGINT",
+                nix::libc::SIGSEGV => "SIGSEGV",
+                nix::libc::SIGABRT => "SIGABRT",
+                _ => "",
+            };
+            return if name.is_empty() {
+                format!("被信号 {} 结束", signal)
+            } else {
+                format!("被信号 {} ({}) 结束", name, signal)
+            };
+        }
+    }
+    "未知的退出方式".into()
+}
+
+/// 生成传递给插件的退出状态环境变量
+pub fn exit_status_env(status: &ExitStatus) -> Vec<(&'static str, String)> {
+    let mut env = Vec::with_capacity(2);
+    if let Some(code) = status.code() {
+        env.push(("HIPER_EXIT_CODE", code.to_string()));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            env.push(("HIPER_EXIT_SIGNAL", signal.to_string()));
+        }
+    }
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_pid_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("hiper-bridge-pid-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn pid_file_round_trip() {
+        let path = temp_pid_file("round-trip");
+        let pid_file = PidFile {
+            pid: 1234,
+            ip: "10.0.0.2".into(),
+            binary: PathBuf::from("/opt/custom/hiper"),
+        };
+        write_pid_file(&path, &pid_file).unwrap();
+        assert_eq!(read_pid_file(&path), Some(pid_file));
+
+        // 其它进程的记录不会被删除
+        remove_pid_file(&path, 4321);
+        assert!(path.exists());
+        remove_pid_file(&path, 1234);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn pid_file_without_binary() {
+        let path = temp_pid_file("legacy");
+        std::fs::write(&path, "1234\n\n").unwrap();
+        assert_eq!(
+            read_pid_file(&path),
+            Some(PidFile {
+                pid: 1234,
+                ip: "".into(),
+                binary: PathBuf::new(),
+            })
+        );
+        std::fs::write(&path, "not a pid\n").unwrap();
+        assert_eq!(read_pid_file(&path), None);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn recognizes_process_by_binary_path() {
+        // 当前测试程序相当于一个使用自定义路径的 HiPer
+        let exe = std::env::current_exe().unwrap();
+        assert!(is_hiper_process(std::process::id(), &exe));
+        assert!(!is_hiper_process(std::process::id(), &exe.with_file_name("hiper")));
+        // 没有记录路径时只接受名称为 HiPer 的程序
+        assert!(!is_hiper_process(std::process::id(), Path::new("")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_pid_is_not_hiper() {
+        let exe = std::env::current_exe().unwrap();
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        assert!(!is_hiper_process(pid, &exe));
+        assert!(HiperProcess::adopt(pid, exe, Duration::from_secs(1)).has_exited());
+    }
+
+    #[test]
+    fn same_binary_compares_full_path() {
+        assert!(is_same_binary(Path::new("/opt/hiper/hiper"), Path::new("/opt/hiper/hiper")));
+        assert!(!is_same_binary(Path::new("/opt/hiper/hiper"), Path::new("/opt/other/hiper")));
+        assert!(is_same_binary(Path::new("/opt/other/hiper"), Path::new("")));
+        assert!(!is_same_binary(Path::new("/opt/other/hiper-old"), Path::new("")));
+    }
+}
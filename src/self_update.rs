@@ -0,0 +1,215 @@
+//! HiPer Bridge 自身的更新
+//!
+//! 发布索引位于下载镜像中所选更新渠道目录下的 `bridge` 目录：`bridge/version` 为最新的版本号，
+//! `bridge/packages.sha1`（以及可选的 `bridge/packages.sha256`）为各架构程序的摘要，
+//! 程序本身位于 `bridge/<架构>/` 下。新版本下载并校验通过后会替换当前的程序，
+//! 没有正在运行的网络时会立即重启到新版本，否则在下次启动时生效
+
+use std::{ path::PathBuf, process::Command, sync::atomic::{ AtomicBool, Ordering } };
+
+use anyhow::Context;
+use druid::{ ExtEventSink, Target };
+use once_cell::sync::Lazy;
+
+use crate::{
+    instance::{ self, PRIMARY_INSTANCE },
+    integrity::VerifiedDownloader,
+    mirror::MirrorList,
+    stage::LaunchStage,
+    ui::{ RESTART_BRIDGE, SET_WARNING },
+    update::UpdateChannel,
+    utils::{ install_running_file, path_with_suffix },
+    DynResult,
+};
+
+/// 当前运行的 HiPer Bridge 版本
+pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// HiPer Bridge 在镜像中的文件名
+#[cfg(windows)]
+pub const BRIDGE_FILE_NAME: &str = "HiPerBridge.exe";
+/// HiPer Bridge 在镜像中的文件名
+#[cfg(not(windows))]
+pub const BRIDGE_FILE_NAME: &str = "hiper-bridge";
+
+/// 启动时的程序路径
+///
+/// 程序被替换后，部分系统上 [`std::env::current_exe`] 会返回被重命名后的旧程序路径，因此需要在启动时记录
+static EXE_PATH: Lazy<Option<PathBuf>> = Lazy::new(|| std::env::current_exe().ok());
+
+/// 退出后是否需要启动替换后的新版本
+static RESTART_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// 发布索引在镜像中的目录
+fn release_dir(channel: UpdateChannel) -> String {
+    match channel.dir() {
+        "" => "bridge".into(),
+        dir => format!("{}/bridge", dir),
+    }
+}
+
+/// 解析 `1.2.3` 或 `v1.2.3` 格式的版本号，预发布等后缀会被忽略
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim().trim_start_matches('v');
+    let version = version.split(['-', '+']).next()?;
+    version
+        .split('.')
+        .map(|x| x.parse().ok())
+        .collect()
+}
+
+/// `latest` 是否比 `current` 更新，无法解析的版本号视为不更新
+pub fn is_newer(latest: &str, current: &str) -> bool {
+    match (parse_version(latest), parse_version(current)) {
+        (Some(latest), Some(current)) => latest > current,
+        _ => false,
+    }
+}
+
+/// 从发布索引获取最新的版本号，已经是最新版本时返回 `None`
+pub fn check_update(
+    ctx: &ExtEventSink,
+    mirrors: &mut MirrorList,
+    channel: UpdateChannel
+) -> DynResult<Option<String>> {
+    let dir = release_dir(channel);
+    let res = mirrors
+        .fetch(ctx, |mirror| mirror.in_dir(&dir).file_url("version"))
+        .context("无法获取 HiPer Bridge 发布索引")?;
+    let latest = res.as_str().context("无法解析 HiPer Bridge 发布索引")?.trim().to_owned();
+    if parse_version(&latest).is_none() {
+        anyhow::bail!("HiPer Bridge 发布索引中的版本号不正确：{}", latest);
+    }
+    crate::log_buffer::info(format!("HiPer Bridge 当前版本 {}，最新版本 {}", CURRENT_VERSION, latest));
+    Ok(Some(latest).filter(|x| is_newer(x, CURRENT_VERSION)))
+}
+
+/// 下载、校验并替换当前的程序，原有的程序会被移动为 `.old` 文件，在下次启动时删除
+pub fn install_update(
+    ctx: &ExtEventSink,
+    mirrors: MirrorList,
+    channel: UpdateChannel
+) -> DynResult<PathBuf> {
+    let exe_path = exe_path()?;
+    let arch = crate::utils::get_system_arch().to_string();
+    let part_path = VerifiedDownloader::new(mirrors, release_dir(channel))
+        .download(ctx, Some(&arch), BRIDGE_FILE_NAME, &exe_path, &mut |_| Ok(()))
+        .context("无法下载 HiPer Bridge")?;
+    install_running_file(&part_path, &exe_path, &path_with_suffix(&exe_path, ".old")).context(
+        "无法替换 HiPer Bridge 程序"
+    )?;
+    Ok(exe_path)
+}
+
+fn exe_path() -> DynResult<PathBuf> {
+    EXE_PATH.to_owned().context("无法获取 HiPer Bridge 程序路径")
+}
+
+/// 删除上次更新留下的旧版本程序，需要在启动时调用
+pub fn remove_old_binary() {
+    if let Ok(exe_path) = exe_path() {
+        let old_path = path_with_suffix(&exe_path, ".old");
+        if old_path.exists() {
+            if let Err(err) = std::fs::remove_file(&old_path) {
+                crate::log_buffer::warn(format!("无法删除旧版本的 HiPer Bridge：{:?}", err));
+            }
+        }
+    }
+}
+
+/// 是否有网络正在运行或启动中，此时重启会中断网络
+fn is_any_busy() -> bool {
+    instance::all()
+        .iter()
+        .any(|x| x.is_running() || x.stage() != LaunchStage::Idle)
+}
+
+/// 在后台线程中检查并安装 HiPer Bridge 的更新
+pub fn update_in_thread(ctx: ExtEventSink, mut mirrors: MirrorList, channel: UpdateChannel) {
+    std::thread::spawn(move || {
+        let latest = match check_update(&ctx, &mut mirrors, channel) {
+            Ok(Some(latest)) => latest,
+            Ok(None) => {
+                return;
+            }
+            Err(err) => {
+                crate::log_buffer::warn(format!("检查 HiPer Bridge 更新失败：{:?}", err));
+                return;
+            }
+        };
+        crate::log_buffer::info(format!("正在更新 HiPer Bridge 到 {}", latest));
+        let message = match install_update(&ctx, mirrors, channel) {
+            Ok(_) if !is_any_busy() => {
+                let _ = ctx.submit_command(RESTART_BRIDGE, (), Target::Auto);
+                return;
+            }
+            Ok(_) => format!("HiPer Bridge 已更新到 v{}，将在下次启动时生效", latest),
+            Err(err) => {
+                crate::log_buffer::warn(format!("更新 HiPer Bridge 失败：{:?}", err));
+                format!("警告：无法更新 HiPer Bridge 到 v{}，请稍后重试或手动下载新版本", latest)
+            }
+        };
+        let _ = ctx.submit_command(SET_WARNING, (PRIMARY_INSTANCE, message), Target::Auto);
+    });
+}
+
+/// 在退出后启动新版本的程序
+pub fn request_restart() {
+    RESTART_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_restart_requested() -> bool {
+    RESTART_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// 以相同的启动参数启动替换后的程序
+pub fn restart() -> DynResult {
+    let exe_path = exe_path()?;
+    Command::new(exe_path)
+        .args(std::env::args_os().skip(1))
+        .spawn()
+        .context("无法启动新版本的 HiPer Bridge")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_version_accepts_prefix_and_suffix() {
+        let cases: &[(&str, Option<&[u64]>)] = &[
+            ("1.2.3", Some(&[1, 2, 3])),
+            ("v0.0.2", Some(&[0, 0, 2])),
+            (" 1.10 \n", Some(&[1, 10])),
+            ("1.2.3-beta.1", Some(&[1, 2, 3])),
+            ("1.2.3+build5", Some(&[1, 2, 3])),
+            ("", None),
+            ("1..2", None),
+            ("1.x", None),
+            ("latest", None),
+        ];
+        for (version, expected) in cases {
+            assert_eq!(parse_version(version).as_deref(), *expected, "{:?}", version);
+        }
+    }
+
+    #[test]
+    fn is_newer_compares_numerically() {
+        let cases = [
+            ("0.0.3", "0.0.2", true),
+            ("0.0.10", "0.0.9", true),
+            ("v1.0.0", "0.9.9", true),
+            ("1.0.1", "1.0", true),
+            ("0.0.2", "0.0.2", false),
+            ("0.0.2-beta", "0.0.2", false),
+            ("0.0.1", "0.0.2", false),
+            ("1.0", "1.0.0", false),
+            ("broken", "0.0.1", false),
+            ("0.0.3", "broken", false),
+        ];
+        for (latest, current, expected) in cases {
+            assert_eq!(is_newer(latest, current), expected, "{} > {}", latest, current);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! HiPer 的启动阶段
+//!
+//! 启动过程中的每一步都对应一个 [`LaunchStage`]，界面、插件和日志都通过阶段判断当前状态，
+//! 而不是比较界面上显示的文字。阶段之间只允许按 [`LaunchStage::can_transition_to`] 中定义的方式切换
+
+use std::fmt::Display;
+
+use druid::Data;
+
+/// 启动过程中的阶段按声明顺序推进，调整顺序会影响 [`LaunchStage::can_transition_to`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Data)]
+pub enum LaunchStage {
+    /// 未启动
+    Idle,
+    /// 正在检查插件更新
+    CheckingPlugins,
+    /// 正在更新插件
+    UpdatingPlugins,
+    /// 正在检查所需文件
+    CheckingFiles,
+    /// 正在下载安装 WinTUN
+    InstallingWintun,
+    /// 正在下载 WinTAP
+    DownloadingWintap,
+    /// 正在安装 WinTAP
+    InstallingWintap,
+    /// 正在检查 HiPer 更新
+    CheckingUpdate,
+    /// 正在更新 HiPer
+    UpdatingHiper,
+    /// 正在安装 HiPer
+    InstallingHiper,
+    /// HiPer 已启动，正在等待入网
+    Joining,
+    /// HiPer 已入网
+    Running,
+    /// 正在关闭 HiPer
+    Stopping,
+}
+
+impl LaunchStage {
+    /// 传递给插件的稳定标识
+    pub fn id(self) -> &'static str {
+        match self {
+            LaunchStage::Idle => "idle",
+            LaunchStage::CheckingPlugins => "checking-plugins",
+            LaunchStage::UpdatingPlugins => "updating-plugins",
+            LaunchStage::CheckingFiles => "checking-files",
+            LaunchStage::InstallingWintun => "installing-wintun",
+            LaunchStage::DownloadingWintap => "downloading-wintap",
+            LaunchStage::InstallingWintap => "installing-wintap",
+            LaunchStage::CheckingUpdate => "checking-update",
+            LaunchStage::UpdatingHiper => "updating-hiper",
+            LaunchStage::InstallingHiper => "installing-hiper",
+            LaunchStage::Joining => "joining",
+            LaunchStage::Running => "running",
+            LaunchStage::Stopping => "stopping",
+        }
+    }
+
+    /// 显示给用户的阶段描述
+    pub fn description(self) -> &'static str {
+        match self {
+            LaunchStage::Idle => "未启动",
+            LaunchStage::CheckingPlugins => "正在检查插件更新",
+            LaunchStage::UpdatingPlugins => "正在更新插件",
+            LaunchStage::CheckingFiles => "正在检查所需文件",
+            LaunchStage::InstallingWintun => "正在下载安装 WinTUN",
+            LaunchStage::DownloadingWintap => "正在下载 WinTAP",
+            LaunchStage::InstallingWintap => "正在安装 WinTAP",
+            LaunchStage::CheckingUpdate => "正在检查 HiPer 更新",
+            LaunchStage::UpdatingHiper => "正在更新 HiPer",
+            LaunchStage::InstallingHiper => "正在安装 HiPer",
+            LaunchStage::Joining => "正在启动 HiPer",
+            LaunchStage::Running => "已入网",
+            LaunchStage::Stopping => "正在关闭 HiPer",
+        }
+    }
+
+    /// 是否处于启动过程中，启动过程中的任意阶段都可以被取消
+    pub fn is_launching(self) -> bool {
+        !matches!(self, LaunchStage::Idle | LaunchStage::Running | LaunchStage::Stopping)
+    }
+
+    /// 是否允许从当前阶段切换到 `next`
+    ///
+    /// - 相同阶段之间的切换用于更新进度信息
+    /// - 任何阶段都可以开始关闭，关闭完成后回到未启动
+    /// - 启动过程只能按顺序向后推进，失败或取消时回到未启动
+    /// - HiPer 意外退出时会从已入网直接回到未启动，之后由守护程序重新开始启动过程
+    /// - 接管已经在运行的 HiPer 时会从未启动直接切换到已入网
+    pub fn can_transition_to(self, next: Self) -> bool {
+        use LaunchStage::*;
+        match (self, next) {
+            (current, next) if current == next => true,
+            (_, Stopping) | (Stopping | Running, Idle) => true,
+            (current, Idle) => current.is_launching(),
+            (Idle, next) if next.is_launching() => true,
+            (current, next) if current.is_launching() && next.is_launching() => {
+                (current as u8) < (next as u8)
+            }
+            (Joining | Idle, Running) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Display for LaunchStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+/// 实例当前的启动阶段和进度信息
+#[derive(Debug, Clone, PartialEq, Eq, Data)]
+pub struct ConnectionState {
+    pub stage: LaunchStage,
+    /// 阶段的进度信息，例如下载进度，没有时为空
+    pub detail: String,
+}
+
+impl Default for ConnectionState {
+    fn default() -> Self {
+        Self::new(LaunchStage::Idle)
+    }
+}
+
+impl ConnectionState {
+    pub fn new(stage: LaunchStage) -> Self {
+        Self {
+            stage,
+            detail: "".into(),
+        }
+    }
+
+    pub fn with_detail(stage: LaunchStage, detail: impl Into<String>) -> Self {
+        Self {
+            stage,
+            detail: detail.into(),
+        }
+    }
+
+    /// 生成传递给插件的环境变量
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        vec![("HIPER_STAGE", self.stage.id().to_owned()), ("HIPER_STAGE_DETAIL", self.detail.to_owned())]
+    }
+}
+
+impl Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.detail.is_empty() {
+            f.write_str(self.stage.description())
+        } else {
+            write!(f, "{}（{}）", self.stage.description(), self.detail)
+        }
+    }
+}
+
+/// 用户取消了正在进行的启动
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LaunchCancelled;
+
+impl Display for LaunchCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("已取消启动 HiPer")
+    }
+}
+
+impl std::error::Error for LaunchCancelled {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use LaunchStage::*;
+
+    const ALL: [LaunchStage; 13] = [
+        Idle,
+        CheckingPlugins,
+        UpdatingPlugins,
+        CheckingFiles,
+        InstallingWintun,
+        DownloadingWintap,
+        InstallingWintap,
+        CheckingUpdate,
+        UpdatingHiper,
+        InstallingHiper,
+        Joining,
+        Running,
+        Stopping,
+    ];
+
+    /// 按 [`ALL`] 的顺序列出每个阶段允许切换到的阶段，`X` 表示允许
+    const TABLE: [&str; 13] = [
+        "XXXXXXXXXXXXX",
+        "XXXXXXXXXXX.X",
+        "X.XXXXXXXXX.X",
+        "X..XXXXXXXX.X",
+        "X...XXXXXXX.X",
+        "X....XXXXXX.X",
+        "X.....XXXXX.X",
+        "X......XXXX.X",
+        "X.......XXX.X",
+        "X........XX.X",
+        "X.........XXX",
+        "X..........XX",
+        "X...........X",
+    ];
+
+    #[test]
+    fn transition_table() {
+        for (from, row) in ALL.iter().zip(TABLE) {
+            for (to, allowed) in ALL.iter().zip(row.chars()) {
+                assert_eq!(
+                    from.can_transition_to(*to),
+                    allowed == 'X',
+                    "{} -> {}",
+                    from.id(),
+                    to.id()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn launching_stages() {
+        let launching: Vec<_> = ALL.iter().filter(|x| x.is_launching()).collect();
+        assert_eq!(launching.len(), 10);
+        assert!(!Idle.is_launching() && !Running.is_launching() && !Stopping.is_launching());
+    }
+}
@@ -0,0 +1,287 @@
+//! HiPer 崩溃守护
+//!
+//! 负责在 HiPer 意外退出后以指数退避的方式重启，
+//! 并在短时间内反复崩溃时停止重启，避免卡住界面和反复触发插件事件
+//!
+//! 每个 HiPer 实例都有自己的 [`Supervisor`]，重启计数互不影响
+
+use std::time::{ Duration, Instant };
+
+use druid::{ ExtEventSink, Target };
+
+use crate::{
+    app_state::AppState,
+    hiper,
+    instance::{ self, InstanceId },
+    launch_options::HiperLaunchOptions,
+    ui::*,
+};
+
+/// 崩溃重启策略
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// 第一次重启前的等待时间
+    pub initial_delay: Duration,
+    /// 每次重启等待时间翻倍后的上限
+    pub max_delay: Duration,
+    /// 连续重启的最大次数，超过后视为崩溃循环
+    pub max_retries: u32,
+    /// HiPer 稳定运行超过该时间后将重置重启计数
+    pub stable_uptime: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(5),
+            max_delay: Duration::from_secs(60),
+            max_retries: 5,
+            stable_uptime: Duration::from_secs(60),
+        }
+    }
+}
+
+impl From<&AppState> for RestartPolicy {
+    fn from(data: &AppState) -> Self {
+        Self {
+            initial_delay: Duration::from_secs(data.restart_delay_secs),
+            max_delay: Duration::from_secs(data.restart_max_delay_secs.max(data.restart_delay_secs)),
+            max_retries: data.restart_max_retries,
+            stable_uptime: Duration::from_secs(data.restart_stable_secs),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// 计算第 `attempt` 次（从 1 开始）重启前需要等待的时间
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.initial_delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartDecision {
+    /// 等待指定时间后进行第 `attempt` 次重启
+    Restart {
+        attempt: u32,
+        delay: Duration,
+    },
+    /// 已经达到最大重启次数，判定为崩溃循环
+    CrashLoop,
+}
+
+#[derive(Debug)]
+pub struct Supervisor {
+    policy: RestartPolicy,
+    attempts: u32,
+    started_at: Option<Instant>,
+    /// 当前的启动是否由守护程序发起且还没有入网
+    restarting: bool,
+    /// 每次用户手动启动或停止都会增加，用于作废还在等待中的重启
+    generation: u64,
+}
+
+impl Supervisor {
+    pub fn new(policy: RestartPolicy) -> Self {
+        Self {
+            policy,
+            attempts: 0,
+            started_at: None,
+            restarting: false,
+            generation: 0,
+        }
+    }
+
+    /// HiPer 成功入网时调用，开始计算稳定运行时间
+    pub fn mark_started(&mut self) {
+        self.started_at = Some(Instant::now());
+        self.restarting = false;
+    }
+
+    /// 守护程序发起的重启还没有入网就结束时返回 `true`，并清除该标记
+    pub fn take_restarting(&mut self) -> bool {
+        std::mem::take(&mut self.restarting)
+    }
+
+    /// HiPer 意外退出时调用，决定是否需要重启以及重启前的等待时间
+    pub fn on_crash(&mut self) -> RestartDecision {
+        if let Some(started_at) = self.started_at.take() {
+            if started_at.elapsed() >= self.policy.stable_uptime {
+                self.attempts = 0;
+            }
+        }
+        if self.attempts >= self.policy.max_retries {
+            return RestartDecision::CrashLoop;
+        }
+        self.attempts += 1;
+        RestartDecision::Restart {
+            attempt: self.attempts,
+            delay: self.policy.delay_for(self.attempts),
+        }
+    }
+
+    /// 清空重启计数并作废等待中的重启，用于用户手动启动 HiPer 时
+    pub fn reset(&mut self, policy: RestartPolicy) {
+        self.policy = policy;
+        self.attempts = 0;
+        self.started_at = None;
+        self.restarting = false;
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    /// 作废还在等待中的重启，用于用户手动停止 HiPer 时
+    pub fn cancel_pending(&mut self) {
+        self.reset(self.policy.to_owned());
+    }
+
+    /// 当前的代数，HiPer 意外退出时记录下来，用于在重启前确认用户没有手动启动或停止过 HiPer
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// 处理 HiPer 意外退出后的重启请求，`generation` 为退出时的代数，见 [`Supervisor::generation`]
+    ///
+    /// 之后用户已经手动启动或停止过 HiPer 时返回 `None`，不再重启
+    pub fn request_restart(&mut self, generation: u64) -> Option<RestartDecision> {
+        if self.generation != generation {
+            return None;
+        }
+        Some(self.on_crash())
+    }
+}
+
+/// 在后台线程中按照实例的重启策略等待并重启 HiPer，不会阻塞界面线程
+///
+/// `generation` 为 HiPer 退出时的代数，见 [`Supervisor::generation`]
+pub fn schedule_restart(ctx: ExtEventSink, id: InstanceId, options: HiperLaunchOptions, generation: u64) {
+    let instance = instance::get(id);
+    let (decision, max_retries) = {
+        let mut supervisor = instance.supervisor();
+        match supervisor.request_restart(generation) {
+            Some(decision) => (decision, supervisor.policy.max_retries),
+            None => {
+                println!("Restart cancelled");
+                return;
+            }
+        }
+    };
+
+    match decision {
+        RestartDecision::Restart { attempt, delay } => {
+            crate::log_buffer::warn(format!(
+                "{} 的 HiPer 意外退出，将在 {} 秒后进行第 {}/{} 次重启",
+                instance.name(),
+                delay.as_secs(),
+                attempt,
+                max_retries
+            ));
+            let _ = ctx.submit_command(
+                SET_WARNING,
+                (
+                    id,
+                    format!(
+                        "警告：HiPer 服务程序已退出！若是意外退出，守护程序将在 {} 秒后自动重启服务程序（第 {}/{} 次）！\n\n　　如需阻止自动重启，请在设置中关闭崩溃重启！",
+                        delay.as_secs(),
+                        attempt,
+                        max_retries
+                    ),
+                ),
+                Target::Auto
+            );
+            std::thread::spawn(move || {
+                std::thread::sleep(delay);
+                {
+                    let mut supervisor = instance.supervisor();
+                    if supervisor.generation != generation {
+                        println!("Restart cancelled");
+                        return;
+                    }
+                    supervisor.restarting = true;
+                }
+                hiper::run_hiper_in_thread(ctx, id, options);
+            });
+        }
+        RestartDecision::CrashLoop => {
+            crate::log_buffer::warn(format!("{} 的 HiPer 反复崩溃，已停止自动重启", instance.name()));
+            instance.dispatch_event("crash-loop", &[]);
+            std::thread::spawn(move || {
+                hiper::stop_hiper(ctx.to_owned(), id);
+                let _ = ctx.submit_command(
+                    SET_WARNING,
+                    (
+                        id,
+                        "错误：HiPer 服务程序在短时间内反复崩溃，已停止自动重启！\n\n　　请检查网络环境或 latest.log 日志文件后手动重新启动。".to_string(),
+                    ),
+                    Target::Auto
+                );
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RestartPolicy {
+        RestartPolicy {
+            initial_delay: Duration::from_secs(5),
+            max_delay: Duration::from_secs(15),
+            max_retries: 3,
+            stable_uptime: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn delay_doubles_up_to_max() {
+        let policy = policy();
+        let delays: Vec<u64> = (1..=5).map(|x| policy.delay_for(x).as_secs()).collect();
+        assert_eq!(delays, [5, 10, 15, 15, 15]);
+        assert_eq!(policy.delay_for(u32::MAX), policy.max_delay);
+    }
+
+    #[test]
+    fn crashes_before_join_count_towards_crash_loop() {
+        let mut supervisor = Supervisor::new(policy());
+        for attempt in 1..=3 {
+            assert!(matches!(supervisor.on_crash(), RestartDecision::Restart { attempt: x, .. } if x == attempt));
+            // 重启后还没有入网就退出，不会重置计数
+            supervisor.restarting = true;
+            assert!(supervisor.take_restarting());
+        }
+        assert_eq!(supervisor.on_crash(), RestartDecision::CrashLoop);
+    }
+
+    #[test]
+    fn restarting_is_cleared_by_join_and_reset() {
+        let mut supervisor = Supervisor::new(policy());
+        supervisor.restarting = true;
+        supervisor.mark_started();
+        assert!(!supervisor.take_restarting());
+
+        supervisor.restarting = true;
+        let generation = supervisor.generation;
+        supervisor.cancel_pending();
+        assert!(!supervisor.take_restarting());
+        assert_ne!(supervisor.generation, generation);
+        assert_eq!(supervisor.attempts, 0);
+    }
+
+    #[test]
+    fn restart_request_is_dropped_after_manual_start_or_stop() {
+        let mut supervisor = Supervisor::new(policy());
+        let generation = supervisor.generation();
+        assert!(matches!(supervisor.request_restart(generation), Some(RestartDecision::Restart { attempt: 1, .. })));
+
+        // HiPer 退出后用户手动停止，之后到达的重启请求不再生效
+        let generation = supervisor.generation();
+        supervisor.cancel_pending();
+        assert_eq!(supervisor.request_restart(generation), None);
+        assert_eq!(supervisor.attempts, 0);
+
+        let generation = supervisor.generation();
+        supervisor.reset(policy());
+        assert_eq!(supervisor.request_restart(generation), None);
+    }
+}
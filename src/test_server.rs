@@ -0,0 +1,107 @@
+//! 测试用的本地 HTTP 服务器
+//!
+//! 在 `127.0.0.1` 的随机端口上提供固定的文件，支持 `Range` 请求，并记录收到的所有请求，
+//! 用于在不连接网络的情况下测试下载、镜像切换、断点续传和代理
+
+use std::{
+    collections::HashMap,
+    io::{ BufRead, BufReader, Write },
+    net::{ TcpListener, TcpStream },
+    sync::{ Arc, Mutex },
+};
+
+/// 服务器收到的一个请求
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// 请求行中的目标，直接请求时为路径，作为代理时为完整的地址
+    pub target: String,
+    /// `Range` 请求头，没有时为 `None`
+    pub range: Option<String>,
+}
+
+pub struct TestServer {
+    port: u16,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl TestServer {
+    /// 启动服务器，`files` 为请求目标到文件内容的映射，其余的请求会返回 404
+    pub fn start(files: &[(&str, &[u8])]) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("无法启动测试服务器");
+        let port = listener.local_addr().unwrap().port();
+        let files: HashMap<String, Vec<u8>> = files
+            .iter()
+            .map(|(target, body)| (target.to_string(), body.to_vec()))
+            .collect();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let requests_c = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                handle(stream, &files, &requests_c);
+            }
+        });
+        Self { port, requests }
+    }
+
+    /// 服务器根地址，不以 `/` 结尾
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().to_owned()
+    }
+}
+
+/// 处理一个连接，请求会在回复之前被记录，以便客户端收到回复后就能检查
+fn handle(stream: TcpStream, files: &HashMap<String, Vec<u8>>, requests: &Mutex<Vec<Request>>) -> Option<()> {
+    let mut reader = BufReader::new(stream.try_clone().ok()?);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let target = line.split_whitespace().nth(1)?.to_owned();
+    let mut range = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("range") {
+                range = Some(value.trim().to_owned());
+            }
+        }
+    }
+
+    requests.lock().unwrap().push(Request {
+        target: target.to_owned(),
+        range: range.to_owned(),
+    });
+
+    let start = range
+        .as_deref()
+        .and_then(|x| x.strip_prefix("bytes="))
+        .and_then(|x| x.trim_end_matches('-').parse::<usize>().ok());
+    let (status, headers, body): (&str, String, &[u8]) = match (files.get(&target), start) {
+        (None, _) => ("404 Not Found", "".into(), &[]),
+        (Some(body), Some(start)) if start >= body.len() =>
+            ("416 Range Not Satisfiable", format!("Content-Range: bytes */{}\r\n", body.len()), &[]),
+        (Some(body), Some(start)) =>
+            (
+                "206 Partial Content",
+                format!("Content-Range: bytes {}-{}/{}\r\n", start, body.len() - 1, body.len()),
+                &body[start..],
+            ),
+        (Some(body), None) => ("200 OK", "".into(), body),
+    };
+    let mut stream = stream;
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        headers,
+        body.len()
+    );
+    let _ = stream.write_all(body);
+    let _ = stream.flush();
+    Some(())
+}
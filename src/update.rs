@@ -0,0 +1,326 @@
+//! HiPer 的更新策略
+//!
+//! 控制启动时是否检查和安装 HiPer 的新版本。每次运行 HiPer Bridge 时，同样的更新设置只会检查一次，
+//! 崩溃重启不会重复检查；两次检查之间的最短间隔会按保存在配置中的上次检查时间计算
+
+use std::{ sync::Mutex, time::Duration };
+
+use druid::Data;
+
+use crate::app_state::AppState;
+
+/// 本次运行中已经检查过的更新设置，见 [`UpdateOptions::source`]
+static CHECKED_SOURCE: Mutex<Option<String>> = Mutex::new(None);
+
+/// 本次运行中已经检查过的更新设置，由 [`UpdateOptions::mark_checked`] 记录
+pub fn checked_source() -> Option<String> {
+    CHECKED_SOURCE.lock().ok()?.to_owned()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Data)]
+pub enum UpdatePolicy {
+    /// 检查并自动安装新版本
+    Auto,
+    /// 只检查新版本并提示，不会自动安装
+    CheckOnly,
+    /// 固定使用指定摘要或版本号的 HiPer
+    Pinned,
+}
+
+impl UpdatePolicy {
+    pub fn id(self) -> &'static str {
+        match self {
+            UpdatePolicy::Auto => "auto",
+            UpdatePolicy::CheckOnly => "check-only",
+            UpdatePolicy::Pinned => "pinned",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<Self> {
+        match id {
+            "auto" => Some(UpdatePolicy::Auto),
+            "check-only" => Some(UpdatePolicy::CheckOnly),
+            "pinned" => Some(UpdatePolicy::Pinned),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            UpdatePolicy::Auto => "自动更新",
+            UpdatePolicy::CheckOnly => "仅检查更新",
+            UpdatePolicy::Pinned => "固定版本",
+        }
+    }
+
+    /// 设置页中按顺序切换到下一个选项
+    pub fn next(self) -> Self {
+        match self {
+            UpdatePolicy::Auto => UpdatePolicy::CheckOnly,
+            UpdatePolicy::CheckOnly => UpdatePolicy::Pinned,
+            UpdatePolicy::Pinned => UpdatePolicy::Auto,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Data)]
+pub enum UpdateChannel {
+    /// 稳定版，位于镜像根目录
+    Stable,
+    /// 测试版，位于镜像的 `beta` 目录
+    Beta,
+}
+
+impl UpdateChannel {
+    pub fn id(self) -> &'static str {
+        match self {
+            UpdateChannel::Stable => "stable",
+            UpdateChannel::Beta => "beta",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<Self> {
+        match id {
+            "stable" => Some(UpdateChannel::Stable),
+            "beta" => Some(UpdateChannel::Beta),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            UpdateChannel::Stable => "稳定版",
+            UpdateChannel::Beta => "测试版",
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            UpdateChannel::Stable => UpdateChannel::Beta,
+            UpdateChannel::Beta => UpdateChannel::Stable,
+        }
+    }
+
+    /// 渠道在镜像中的目录，稳定版为空
+    pub fn dir(self) -> &'static str {
+        match self {
+            UpdateChannel::Stable => "",
+            UpdateChannel::Beta => "beta",
+        }
+    }
+}
+
+/// 判断固定的版本是否是一个 SHA-1 摘要
+fn is_sha1(pinned: &str) -> bool {
+    pinned.len() == 40 && pinned.chars().all(|x| x.is_ascii_hexdigit())
+}
+
+/// 一次启动时使用的更新设置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateOptions {
+    pub policy: UpdatePolicy,
+    pub channel: UpdateChannel,
+    /// 固定的 HiPer 版本，可以是 SHA-1 摘要或者版本号，仅在 [`UpdatePolicy::Pinned`] 时有效
+    pub pinned: String,
+    /// 两次检查更新之间的最短间隔
+    pub check_interval: Duration,
+    /// 上次检查更新的时间戳（秒），从未检查过时为 `None`
+    pub last_check: Option<i64>,
+}
+
+impl Default for UpdateOptions {
+    fn default() -> Self {
+        Self {
+            policy: UpdatePolicy::Auto,
+            channel: UpdateChannel::Stable,
+            pinned: "".into(),
+            check_interval: Duration::ZERO,
+            last_check: None,
+        }
+    }
+}
+
+impl From<&AppState> for UpdateOptions {
+    fn from(data: &AppState) -> Self {
+        Self {
+            policy: data.update_policy,
+            channel: data.update_channel,
+            pinned: data.pinned_version.trim().to_owned(),
+            check_interval: Duration::from_secs(data.update_check_interval_hours * 60 * 60),
+            last_check: if data.last_update_check > 0 { Some(data.last_update_check) } else { None },
+        }
+    }
+}
+
+impl UpdateOptions {
+    pub fn is_pinned(&self) -> bool {
+        self.policy == UpdatePolicy::Pinned && !self.pinned.is_empty()
+    }
+
+    /// 固定的 SHA-1 摘要，固定的是版本号时为 `None`
+    pub fn pinned_hash(&self) -> Option<String> {
+        if self.is_pinned() && is_sha1(&self.pinned) {
+            Some(self.pinned.to_ascii_lowercase())
+        } else {
+            None
+        }
+    }
+
+    /// 下载索引和 HiPer 程序所在的镜像目录
+    ///
+    /// 固定版本号时为 `versions/<版本号>`，否则为所选渠道的目录
+    pub fn index_dir(&self) -> String {
+        if self.is_pinned() && !is_sha1(&self.pinned) {
+            format!("versions/{}", self.pinned)
+        } else {
+            self.channel.dir().to_owned()
+        }
+    }
+
+    /// 用于判断本次运行中是否已经按相同设置检查过更新
+    fn source(&self) -> String {
+        if self.is_pinned() {
+            format!("pinned:{}", self.pinned)
+        } else {
+            format!("{}:{}", self.policy.id(), self.channel.id())
+        }
+    }
+
+    /// 记录本次运行中已经完成了一次检查
+    pub fn mark_checked(&self) {
+        if let Ok(mut checked) = CHECKED_SOURCE.lock() {
+            *checked = Some(self.source());
+        }
+    }
+
+    /// 是否需要从镜像获取下载索引
+    ///
+    /// `checked_source` 为本次运行中已经检查过的更新设置，见 [`checked_source`]
+    ///
+    /// - 没有安装 HiPer 时总是需要
+    /// - 固定版本但没有填写版本时保持现有的 HiPer 不变
+    /// - 固定摘要时仅在现有程序的摘要不一致时需要
+    /// - 其余情况下同样的设置每次运行只检查一次，并且需要距离上次检查超过最短间隔
+    pub fn needs_check(&self, current_hash: Option<&str>, checked_source: Option<&str>, now: i64) -> bool {
+        let current_hash = match current_hash {
+            Some(hash) => hash,
+            None => {
+                return true;
+            }
+        };
+        if self.policy == UpdatePolicy::Pinned && self.pinned.is_empty() {
+            return false;
+        }
+        if let Some(pinned) = self.pinned_hash() {
+            return pinned != current_hash;
+        }
+        if checked_source == Some(self.source().as_str()) {
+            return false;
+        }
+        if self.is_pinned() {
+            return true;
+        }
+        match self.last_check {
+            Some(last_check) => now.saturating_sub(last_check) >= (self.check_interval.as_secs() as i64),
+            None => true,
+        }
+    }
+
+    /// 是否应当安装下载索引中的版本
+    pub fn should_install(&self, current_hash: Option<&str>, latest_hash: &str) -> bool {
+        match current_hash {
+            None => true,
+            Some(current_hash) => {
+                current_hash != latest_hash && self.policy != UpdatePolicy::CheckOnly
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+    const CURRENT: &str = "0123456789abcdef0123456789abcdef01234567";
+    const LATEST: &str = "89abcdef0123456789abcdef0123456789abcdef";
+
+    fn update_options(policy: UpdatePolicy, channel: UpdateChannel, pinned: &str) -> UpdateOptions {
+        UpdateOptions {
+            policy,
+            channel,
+            pinned: pinned.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn missing_hiper_is_always_checked_and_installed() {
+        for policy in [UpdatePolicy::Auto, UpdatePolicy::CheckOnly, UpdatePolicy::Pinned] {
+            let options = update_options(policy, UpdateChannel::Stable, "");
+            assert!(options.needs_check(None, Some(&options.source()), NOW));
+            assert!(options.should_install(None, LATEST));
+        }
+    }
+
+    #[test]
+    fn pinned_hash_equal_to_current_is_not_checked() {
+        let options = update_options(UpdatePolicy::Pinned, UpdateChannel::Stable, &CURRENT.to_uppercase());
+        assert_eq!(options.pinned_hash().as_deref(), Some(CURRENT));
+        assert_eq!(options.index_dir(), "");
+        assert!(!options.needs_check(Some(CURRENT), None, NOW));
+        // 摘要不一致时即使本次运行已经检查过也需要重新获取
+        assert!(options.needs_check(Some(LATEST), Some(&options.source()), NOW));
+        assert!(options.should_install(Some(LATEST), CURRENT));
+
+        let empty = update_options(UpdatePolicy::Pinned, UpdateChannel::Stable, "");
+        assert!(!empty.needs_check(Some(CURRENT), None, NOW));
+    }
+
+    #[test]
+    fn pinned_version_uses_version_dir() {
+        let options = update_options(UpdatePolicy::Pinned, UpdateChannel::Beta, "1.2.3");
+        assert_eq!(options.pinned_hash(), None);
+        assert_eq!(options.index_dir(), "versions/1.2.3");
+        assert!(options.needs_check(Some(CURRENT), None, NOW));
+        assert!(!options.needs_check(Some(CURRENT), Some(&options.source()), NOW));
+        assert!(!options.should_install(Some(CURRENT), CURRENT));
+        assert!(options.should_install(Some(CURRENT), LATEST));
+    }
+
+    #[test]
+    fn check_only_never_replaces_installed_hiper() {
+        let options = update_options(UpdatePolicy::CheckOnly, UpdateChannel::Stable, "");
+        assert!(options.needs_check(Some(CURRENT), None, NOW));
+        assert!(!options.should_install(Some(CURRENT), LATEST));
+        assert!(!options.should_install(Some(CURRENT), CURRENT));
+    }
+
+    #[test]
+    fn channel_switch_checks_again() {
+        let stable = update_options(UpdatePolicy::Auto, UpdateChannel::Stable, "");
+        let beta = update_options(UpdatePolicy::Auto, UpdateChannel::Beta, "");
+        assert_eq!(beta.index_dir(), "beta");
+        assert!(!stable.needs_check(Some(CURRENT), Some(&stable.source()), NOW));
+        assert!(beta.needs_check(Some(CURRENT), Some(&stable.source()), NOW));
+        // 切换更新策略同样视为不同的设置
+        let check_only = update_options(UpdatePolicy::CheckOnly, UpdateChannel::Stable, "");
+        assert!(check_only.needs_check(Some(CURRENT), Some(&stable.source()), NOW));
+    }
+
+    #[test]
+    fn check_interval_is_respected() {
+        let options = UpdateOptions {
+            check_interval: Duration::from_secs(60 * 60),
+            last_check: Some(NOW - 60 * 60 + 1),
+            ..Default::default()
+        };
+        assert!(!options.needs_check(Some(CURRENT), None, NOW));
+        assert!(options.needs_check(Some(CURRENT), None, NOW + 1));
+        let never = UpdateOptions {
+            last_check: None,
+            ..options.to_owned()
+        };
+        assert!(never.needs_check(Some(CURRENT), None, NOW));
+    }
+}
@@ -1,6 +1,10 @@
 //! 一些常用的玩意
 
-use std::{fmt::Display, io::Write, path::Path};
+use std::{
+    fmt::Display,
+    io::Write,
+    path::{Path, PathBuf},
+};
 
 /// 安全写入文件数据，写入完成后会等待文件缓冲区完全写入才关闭文件
 pub fn write_file_safe(p: impl AsRef<Path>, data: &[u8]) -> Result<(), std::io::Error> {
@@ -15,6 +19,87 @@ pub fn write_file_safe(p: impl AsRef<Path>, data: &[u8]) -> Result<(), std::io::
     Ok(())
 }
 
+/// 在文件名后追加后缀，例如 `hiper.exe` 追加 `.bak` 后为 `hiper.exe.bak`
+pub fn path_with_suffix(p: impl AsRef<Path>, suffix: &str) -> PathBuf {
+    let p = p.as_ref();
+    let mut file_name = p.file_name().unwrap_or_default().to_os_string();
+    file_name.push(suffix);
+    p.with_file_name(file_name)
+}
+
+/// 原子地替换可执行文件，数据会先写入同目录下的临时文件，完成后再重命名到目标位置，
+/// 写入中断时原有文件不会被破坏
+///
+/// 提供 `backup` 时原有文件会被移动到该位置作为备份
+pub fn replace_file_atomic(
+    p: impl AsRef<Path>,
+    data: &[u8],
+    backup: Option<&Path>,
+) -> Result<(), std::io::Error> {
+    let p = p.as_ref();
+    let tmp_path = path_with_suffix(p, ".tmp");
+    write_file_safe(&tmp_path, data)?;
+    install_file(&tmp_path, p, backup)
+}
+
+/// 将同目录下已经准备好的文件（例如下载完成的临时文件）重命名到目标位置，并设置为可执行
+///
+/// 提供 `backup` 时原有文件会先被硬链接（不支持时复制）到该位置作为备份，
+/// 之后只需一次重命名即可覆盖目标，任何时刻目标位置都存在完整的文件
+pub fn install_file(
+    prepared: &Path,
+    p: impl AsRef<Path>,
+    backup: Option<&Path>,
+) -> Result<(), std::io::Error> {
+    let p = p.as_ref();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(prepared, std::fs::Permissions::from_mode(0o755))?;
+    }
+    if let Some(backup) = backup.filter(|_| p.exists()) {
+        let _ = std::fs::remove_file(backup);
+        if std::fs::hard_link(p, backup).is_err() {
+            std::fs::copy(p, backup)?;
+        }
+    }
+    if let Err(err) = std::fs::rename(prepared, p) {
+        let _ = std::fs::remove_file(prepared);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// 替换正在运行的程序：原有文件先被重命名到 `old`，再将 `prepared` 重命名到目标位置
+///
+/// Windows 下正在运行的程序无法被覆盖或删除，但可以被重命名，因此不能使用 [`install_file`]。
+/// 第二次重命名失败时会将原有文件恢复到目标位置
+pub fn install_running_file(
+    prepared: &Path,
+    p: impl AsRef<Path>,
+    old: &Path,
+) -> Result<(), std::io::Error> {
+    let p = p.as_ref();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(prepared, std::fs::Permissions::from_mode(0o755))?;
+    }
+    let has_old = p.exists();
+    if has_old {
+        let _ = std::fs::remove_file(old);
+        std::fs::rename(p, old)?;
+    }
+    if let Err(err) = std::fs::rename(prepared, p) {
+        if has_old {
+            let _ = std::fs::rename(old, p);
+        }
+        let _ = std::fs::remove_file(prepared);
+        return Err(err);
+    }
+    Ok(())
+}
+
 pub enum Arch {
     X86,
     X64,
@@ -68,3 +153,97 @@ pub fn get_system_arch() -> Arch {
     #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
     return Arch::ARM64;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "hiper-bridge-utils-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn install_file_keeps_backup_of_old_file() {
+        let dir = temp_dir("backup");
+        let target = dir.join("hiper");
+        let backup = dir.join("hiper.bak");
+        std::fs::write(&target, b"old").unwrap();
+        std::fs::write(&backup, b"older").unwrap();
+
+        replace_file_atomic(&target, b"new", Some(&backup)).unwrap();
+
+        assert_eq!(std::fs::read(&target).unwrap(), b"new");
+        assert_eq!(std::fs::read(&backup).unwrap(), b"old");
+        assert!(!path_with_suffix(&target, ".tmp").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn install_file_without_existing_target() {
+        let dir = temp_dir("fresh");
+        let target = dir.join("hiper");
+        let backup = dir.join("hiper.bak");
+
+        replace_file_atomic(&target, b"new", Some(&backup)).unwrap();
+
+        assert_eq!(std::fs::read(&target).unwrap(), b"new");
+        assert!(!backup.exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn install_running_file_moves_old_file_aside() {
+        let dir = temp_dir("running");
+        let target = dir.join("bridge");
+        let old = dir.join("bridge.old");
+        let part = dir.join("bridge.part");
+        std::fs::write(&target, b"old").unwrap();
+        std::fs::write(&old, b"older").unwrap();
+        std::fs::write(&part, b"new").unwrap();
+
+        install_running_file(&part, &target, &old).unwrap();
+
+        assert_eq!(std::fs::read(&target).unwrap(), b"new");
+        assert_eq!(std::fs::read(&old).unwrap(), b"old");
+        assert!(!part.exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn install_running_file_restores_old_file_on_failure() {
+        let dir = temp_dir("running-failure");
+        // 文件夹无法被重命名到自身之内，用于让第二次重命名失败
+        let part = dir.join("bridge.part");
+        std::fs::create_dir_all(&part).unwrap();
+        let target = part.join("bridge");
+        let old = dir.join("bridge.old");
+        std::fs::write(&target, b"old").unwrap();
+
+        assert!(install_running_file(&part, &target, &old).is_err());
+
+        assert_eq!(std::fs::read(&target).unwrap(), b"old");
+        assert!(!old.exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn install_file_failure_leaves_target_untouched() {
+        let dir = temp_dir("failure");
+        let target = dir.join("hiper");
+        let backup = dir.join("hiper.bak");
+        std::fs::write(&target, b"old").unwrap();
+
+        let missing = dir.join("missing.part");
+        assert!(install_file(&missing, &target, Some(&backup)).is_err());
+
+        assert_eq!(std::fs::read(&target).unwrap(), b"old");
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}